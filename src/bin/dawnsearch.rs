@@ -17,22 +17,67 @@
    along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use anyhow::bail;
+use anyhow::{bail, Context};
 use dawnsearch::config::Config;
-use dawnsearch::embedding::embedding_service::{EmbeddingMsg, EmbeddingService};
+use dawnsearch::embedding::embedding_model::ModelSettings;
+use dawnsearch::embedding::embedding_service::{
+    embedding_channel, load_embedding_model, EmbeddingService,
+};
 use dawnsearch::index::extraction_service::start_extraction_service;
 use dawnsearch::net::http_service::start_http_service;
 use dawnsearch::net::udp_service::{UdpMsg, UdpService};
 use dawnsearch::search::search_msg::SearchMsg;
 use dawnsearch::search::search_msg::SearchMsg::*;
-use dawnsearch::search::search_service::SearchService;
-use std::time::Duration;
+use dawnsearch::search::search_service::{max_pending_pages, SearchService};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{env, fs};
 use tokio::select;
 use tokio::signal::unix::signal;
 use tokio::signal::unix::SignalKind;
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 
+/** How long we wait before restarting a service that failed. */
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+/** A service that fails within this time after it was started, failed quickly. */
+const QUICK_FAILURE: Duration = Duration::from_secs(60);
+/** After this many quick failures in a row we give up on the whole process. */
+const MAX_QUICK_FAILURES: usize = 5;
+
+/**
+ * Run a service, and restart it when it returns an error or panics. A service that returns Ok
+ * stopped on purpose and is not restarted. When a service keeps failing right after it was
+ * started, the process exits instead of running without it.
+ */
+async fn supervise<F>(name: &'static str, mut start: F)
+where
+    F: FnMut() -> JoinHandle<anyhow::Result<()>>,
+{
+    let mut quick_failures = 0;
+    loop {
+        let started = Instant::now();
+        let error = match start().await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => format!("{:#}", e),
+            // The service panicked.
+            Err(e) => e.to_string(),
+        };
+        eprintln!("[Main] The {} service failed: {}", name, error);
+        if started.elapsed() < QUICK_FAILURE {
+            quick_failures += 1;
+        } else {
+            quick_failures = 1;
+        }
+        if quick_failures >= MAX_QUICK_FAILURES {
+            eprintln!("[Main] The {} service keeps failing, exiting", name);
+            std::process::exit(1);
+        }
+        tokio::time::sleep(RESTART_DELAY).await;
+        println!("[Main] Restarting the {} service", name);
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let args: Vec<String> = env::args().collect();
@@ -52,30 +97,62 @@ async fn main() -> Result<(), anyhow::Error> {
 
     fs::create_dir_all(&config.data_dir)?;
 
+    // Nothing works without the model, so stop right away when it can't be loaded.
+    let model_settings = ModelSettings::from_config(&config);
+    let model = tokio::task::spawn_blocking(move || load_embedding_model(&model_settings))
+        .await?
+        .context("Failed to load the embedding model")?;
+
     let original_shutdown_token = CancellationToken::new();
 
     let shutdown_token = original_shutdown_token.clone();
 
-    let (embedding_tx, embedding_rx) = std::sync::mpsc::sync_channel::<EmbeddingMsg>(2);
+    // Room for all pages waiting for their embeddings, so the search service never blocks.
+    let (embedding_tx, embedding_rx) = embedding_channel(max_pending_pages(&config));
     let (search_tx, search_rx) = std::sync::mpsc::sync_channel::<SearchMsg>(2);
+    // Pages to index have their own channel, so searches don't wait behind them.
+    let (index_tx, index_rx) = std::sync::mpsc::sync_channel::<SearchMsg>(2);
     let (udp_tx, mut udp_rx) = tokio::sync::mpsc::channel::<UdpMsg>(2);
 
-    let mut embedding_service = EmbeddingService { embedding_rx };
-    tokio::task::spawn_blocking(move || {
-        embedding_service.start();
-    });
+    for _ in 0..config.embedding_workers {
+        let embedding_rx = embedding_rx.clone();
+        let model = model.clone();
+        let config = config.clone();
+        tokio::spawn(supervise("embedding", move || {
+            let mut embedding_service = EmbeddingService {
+                embedding_rx: embedding_rx.clone(),
+                model: model.clone(),
+                batch_size: config.embedding_batch_size,
+                batch_latency: Duration::from_millis(config.embedding_batch_latency_ms),
+                debug: config.debug,
+            };
+            tokio::task::spawn_blocking(move || {
+                embedding_service.start();
+                Ok(())
+            })
+        }));
+    }
 
-    let mut search_service = SearchService {
-        config: config.clone(),
-        shutdown_token,
-        search_rx,
-        search_tx: search_tx.clone(),
-        udp_tx: udp_tx.clone(),
-        embedding_tx: embedding_tx.clone(),
-    };
-    tokio::task::spawn_blocking(move || {
-        search_service.start();
-    });
+    let search_rx = Arc::new(Mutex::new(search_rx));
+    let index_rx = Arc::new(Mutex::new(index_rx));
+    {
+        let config = config.clone();
+        let search_tx = search_tx.clone();
+        let udp_tx = udp_tx.clone();
+        let embedding_tx = embedding_tx.clone();
+        tokio::spawn(supervise("search", move || {
+            let mut search_service = SearchService {
+                config: config.clone(),
+                shutdown_token: shutdown_token.clone(),
+                search_rx: search_rx.clone(),
+                index_rx: index_rx.clone(),
+                search_tx: search_tx.clone(),
+                udp_tx: udp_tx.clone(),
+                embedding_tx: embedding_tx.clone(),
+            };
+            tokio::task::spawn_blocking(move || search_service.start())
+        }));
+    }
 
     let tx2 = search_tx.clone();
     tokio::spawn(async move {
@@ -88,49 +165,58 @@ async fn main() -> Result<(), anyhow::Error> {
     });
 
     if config.index_cc_enabled {
-        let tx2 = search_tx.clone();
-        tokio::spawn(async move {
-            start_extraction_service(tx2).await.unwrap();
-        });
+        let tx2 = index_tx.clone();
+        let max_text_len = config.max_text_len;
+        tokio::spawn(supervise("extraction", move || {
+            tokio::spawn(start_extraction_service(tx2.clone(), max_text_len))
+        }));
     }
 
-    let config2 = config.clone();
     if config.web_enabled {
         let tx2 = search_tx.clone();
-        tokio::spawn(async move {
-            start_http_service(tx2, config2).await.unwrap();
-        });
+        let config2 = config.clone();
+        tokio::spawn(supervise("web", move || {
+            tokio::spawn(start_http_service(tx2.clone(), config2.clone()))
+        }));
     }
 
     if config.udp_enabled {
-        let udp_service = UdpService {
-            search_tx: search_tx.clone(),
-            udp_rx,
-            config,
-        };
-        tokio::spawn(udp_service.start());
+        let tx2 = search_tx.clone();
+        let udp_rx = Arc::new(tokio::sync::Mutex::new(udp_rx));
+        tokio::spawn(supervise("UDP", move || {
+            let udp_service = UdpService {
+                search_tx: tx2.clone(),
+                index_tx: index_tx.clone(),
+                udp_rx: udp_rx.clone(),
+                config: config.clone(),
+            };
+            tokio::spawn(udp_service.start())
+        }));
 
         // Timer loop.
         let udp_tx2 = udp_tx.clone();
         tokio::spawn(async move {
             loop {
                 tokio::time::sleep(Duration::from_millis(50)).await;
-                udp_tx2.send(UdpMsg::Tick {}).await.unwrap();
+                if udp_tx2.send(UdpMsg::Tick {}).await.is_err() {
+                    break;
+                }
             }
         });
         // Announce loop.
         let udp_tx2 = udp_tx.clone();
         tokio::spawn(async move {
             loop {
-                udp_tx2.send(UdpMsg::Announce {}).await.unwrap();
+                if udp_tx2.send(UdpMsg::Announce {}).await.is_err() {
+                    break;
+                }
                 tokio::time::sleep(Duration::from_secs(60)).await;
             }
         });
     } else {
         // Discard messages.
         tokio::spawn(async move {
-            loop {
-                let discarded = udp_rx.recv().await.unwrap();
+            while let Some(discarded) = udp_rx.recv().await {
                 if config.debug > 0 {
                     println!("[Main] Discarding packet for UDP system {:?}", discarded);
                 }
@@ -72,6 +72,7 @@ async fn main() -> anyhow::Result<()> {
                 instance_id,
                 accept_insert,
                 pages_indexed,
+                model,
             } => {
                 println!("Announce ID {} addr {}", instance_id, addr);
                 if let Some(x) = &external_address {
@@ -88,6 +89,7 @@ async fn main() -> anyhow::Result<()> {
                         last_seen: now(),
                         accept_insert,
                         pages_indexed,
+                        model,
                     },
                 );
                 let all: Vec<PeerInfo> = peers
@@ -95,8 +97,8 @@ async fn main() -> anyhow::Result<()> {
                     .filter(|p| p.instance_id != instance_id && now() - p.last_seen < 10 * 60)
                     .map(|x| x.clone())
                     .collect();
-                for chunk in all.chunks(25) {
-                    // We can probably fit 40 but let's be careful.
+                for chunk in all.chunks(10) {
+                    // We can probably fit 14 with the model names but let's be careful.
                     let response = UdpPacket::Peers {
                         peers: chunk.to_vec(),
                     };
@@ -19,13 +19,32 @@
 
 use std::fs;
 
+use crate::embedding::embedding_model::{
+    DEFAULT_DIMENSION, DEFAULT_MODEL_ID, DEFAULT_MODEL_REVISION,
+};
+use crate::search::page_source::PAGE_START_LEN;
+
 #[derive(Clone)]
 pub struct Config {
     pub config_file: String,
 
     pub index_cc_enabled: bool,
+    /**
+     * Bytes of the text of a page from Common Crawl that we keep. The text after the first
+     * PAGE_START_LEN bytes is indexed in passages, which each need an embedding.
+     */
+    pub max_text_len: usize,
     pub web_enabled: bool,
     pub web_listen_address: String,
+    /** Where users reach the web interface, like https://dawnsearch.org. Empty to use the Host header. */
+    pub web_url: String,
+    /** Requests that take longer than this get a 408 response. */
+    pub web_timeout_secs: u64,
+    /**
+     * Suggest queries to others once they have been searched this many times. 0, the default,
+     * only suggests page titles.
+     */
+    pub suggest_min_searches: usize,
 
     pub udp_enabled: bool,
     pub udp_listen_address: String,
@@ -35,6 +54,50 @@ pub struct Config {
     pub trackers: Vec<String>,
     pub data_dir: String,
 
+    /** Scalar type of the vectors in the usearch index: f32, f16 or i8. */
+    pub index_quantization: String,
+    /** Format of the embeddings stored in the database: f32, i24 or i16. */
+    pub embedding_storage: String,
+
+    /** Stop adding pages when we have this many. */
+    pub max_pages: usize,
+    /** Stop adding pages when the database and index use this much disk space, 0 for no limit. */
+    pub max_disk_size_mb: usize,
+    /**
+     * Pages with an embedding closer than this to an existing page with another URL are
+     * stored as a duplicate of that page. 0 only detects exact copies.
+     */
+    pub duplicate_distance: f64,
+    /** Which pages to remove when we are full: none, oldest, least_returned or farthest. */
+    pub eviction: String,
+    /** HNSW parameters of the index, 0 means the usearch default. */
+    pub hnsw_connectivity: usize,
+    pub hnsw_expansion_add: usize,
+    pub hnsw_expansion_search: usize,
+
+    /** Hugging Face id and revision of the sentence-transformers model. */
+    pub model_id: String,
+    pub model_revision: String,
+    /** Length of the embeddings of the model. */
+    pub embedding_dimension: usize,
+    /** Directory with config.json, tokenizer.json and model.safetensors. Empty to use Hugging Face. */
+    pub model_dir: String,
+    /** Only take the model from the Hugging Face cache. */
+    pub model_offline: bool,
+    /** Calculate at most this many embeddings in one go. */
+    pub embedding_batch_size: usize,
+    /** How long to wait for more texts after the first one before calculating a batch. */
+    pub embedding_batch_latency_ms: u64,
+    /** Number of threads calculating embeddings, they share the model. */
+    pub embedding_workers: usize,
+    /** Number of query and remote page embeddings to keep, 0 to disable the cache. */
+    pub embedding_cache_size: usize,
+    /**
+     * Keep the embedding cache in the data directory across restarts. The text of recent queries
+     * is then stored on disk.
+     */
+    pub embedding_cache_persist: bool,
+
     pub debug: usize,
 }
 
@@ -52,10 +115,16 @@ impl Config {
         Config {
             config_file: used_config_file.to_string(),
             index_cc_enabled: settings.get_bool("index_cc").unwrap_or(false),
+            max_text_len: settings
+                .get_int("max_text_len")
+                .unwrap_or(PAGE_START_LEN as i64) as usize,
             web_enabled: settings.get_bool("web").unwrap_or(true),
             web_listen_address: settings
                 .get_string("web_listen_address")
                 .unwrap_or("0.0.0.0:8080".to_string()),
+            web_url: settings.get_string("web_url").unwrap_or_default(),
+            web_timeout_secs: settings.get_int("web_timeout_secs").unwrap_or(30) as u64,
+            suggest_min_searches: settings.get_int("suggest_min_searches").unwrap_or(0) as usize,
             udp_enabled: settings.get_bool("udp").unwrap_or(true),
             udp_listen_address: settings
                 .get_string("udp_listen_address")
@@ -68,6 +137,41 @@ impl Config {
                 .map(|a| a.iter().map(|v| v.clone().into_string().unwrap()).collect())
                 .unwrap_or_default(),
             data_dir: settings.get_string("data_dir").unwrap_or(".".to_string()),
+            index_quantization: settings
+                .get_string("index_quantization")
+                .unwrap_or("f32".to_string()),
+            embedding_storage: settings
+                .get_string("embedding_storage")
+                .unwrap_or("f32".to_string()),
+            max_pages: settings.get_int("max_pages").unwrap_or(1000000) as usize,
+            max_disk_size_mb: settings.get_int("max_disk_size_mb").unwrap_or(0) as usize,
+            duplicate_distance: settings.get_float("duplicate_distance").unwrap_or(0.02),
+            eviction: settings
+                .get_string("eviction")
+                .unwrap_or("none".to_string()),
+            hnsw_connectivity: settings.get_int("hnsw_connectivity").unwrap_or(0) as usize,
+            hnsw_expansion_add: settings.get_int("hnsw_expansion_add").unwrap_or(0) as usize,
+            hnsw_expansion_search: settings.get_int("hnsw_expansion_search").unwrap_or(0) as usize,
+            model_id: settings
+                .get_string("model_id")
+                .unwrap_or(DEFAULT_MODEL_ID.to_string()),
+            model_revision: settings
+                .get_string("model_revision")
+                .unwrap_or(DEFAULT_MODEL_REVISION.to_string()),
+            embedding_dimension: settings
+                .get_int("embedding_dimension")
+                .unwrap_or(DEFAULT_DIMENSION as i64) as usize,
+            model_dir: settings.get_string("model_dir").unwrap_or_default(),
+            model_offline: settings.get_bool("model_offline").unwrap_or(false),
+            embedding_batch_size: settings.get_int("embedding_batch_size").unwrap_or(32) as usize,
+            embedding_batch_latency_ms: settings.get_int("embedding_batch_latency_ms").unwrap_or(10)
+                as u64,
+            embedding_workers: settings.get_int("embedding_workers").unwrap_or(1).max(1) as usize,
+            embedding_cache_size: settings.get_int("embedding_cache_size").unwrap_or(10000)
+                as usize,
+            embedding_cache_persist: settings
+                .get_bool("embedding_cache_persist")
+                .unwrap_or(false),
             debug: settings.get_int("debug").unwrap_or(0) as usize,
         }
     }
@@ -76,13 +180,46 @@ impl Config {
         println!("==========================================================");
         println!("Config file: {}", self.config_file);
         println!("Indexing Common Crawl enabled: {}", self.index_cc_enabled);
+        println!("Max text length: {} bytes", self.max_text_len);
         println!("Web enabled: {}", self.web_enabled);
         println!("Web listen address: {}", self.web_listen_address);
+        println!("Web URL: {}", self.web_url);
+        println!("Web timeout: {} seconds", self.web_timeout_secs);
+        println!(
+            "Suggest queries searched at least: {} times",
+            self.suggest_min_searches
+        );
         println!("UDP enabled: {}", self.udp_enabled);
         println!("UDP listen address: {}", self.udp_listen_address);
         println!("UPnP enabled: {}", self.upnp_enabled);
         println!("Trackers: {:?}", self.trackers);
         println!("Data directory: {}", self.data_dir);
+        println!("Index quantization: {}", self.index_quantization);
+        println!("Embedding storage: {}", self.embedding_storage);
+        println!("Max pages: {}", self.max_pages);
+        println!("Max disk size (MB): {}", self.max_disk_size_mb);
+        println!("Duplicate distance: {}", self.duplicate_distance);
+        println!("Eviction: {}", self.eviction);
+        println!(
+            "HNSW connectivity: {} expansion add: {} expansion search: {}",
+            self.hnsw_connectivity, self.hnsw_expansion_add, self.hnsw_expansion_search
+        );
+        println!(
+            "Model: {} revision: {} dimension: {}",
+            self.model_id, self.model_revision, self.embedding_dimension
+        );
+        println!(
+            "Model directory: {} offline: {}",
+            self.model_dir, self.model_offline
+        );
+        println!(
+            "Embedding batch size: {} latency: {} ms workers: {}",
+            self.embedding_batch_size, self.embedding_batch_latency_ms, self.embedding_workers
+        );
+        println!(
+            "Embedding cache size: {} persist: {}",
+            self.embedding_cache_size, self.embedding_cache_persist
+        );
         println!("Debug level: {}", self.debug);
         println!("==========================================================");
     }
@@ -0,0 +1,325 @@
+/*
+   Copyright 2023 huggingface/candle (Apache 2.0 / MIT)
+   Copyright 2023 Krol Inventions B.V.
+
+   This file is part of DawnSearch.
+
+   DawnSearch is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   DawnSearch is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::iter::{repeat, zip};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Error as E, Result};
+use candle::{Device, Tensor};
+use candle_nn::VarBuilder;
+use hf_hub::{api::sync::Api, Cache, Repo, RepoType};
+use tokenizers::Tokenizer;
+
+use crate::config::Config;
+use crate::embedding::model::{self, BertModel, DTYPE};
+use crate::search::vector::normalize;
+
+/** The model DawnSearch used before the model could be configured. */
+pub const DEFAULT_MODEL_ID: &str = "sentence-transformers/all-MiniLM-L6-v2";
+pub const DEFAULT_MODEL_REVISION: &str = "refs/pr/21";
+pub const DEFAULT_DIMENSION: usize = 384;
+
+/**
+ * Turns texts into embeddings. There is an implementation for each architecture we support.
+ * A model is shared by the embedding workers.
+ */
+pub trait EmbeddingModel: Send + Sync {
+    /** Length of the embeddings. */
+    fn dimension(&self) -> usize;
+
+    /** Normalized embeddings of the texts, in the same order as the texts. */
+    fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>>;
+}
+
+/** Which model to use. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelSettings {
+    /** Hugging Face model id, for example sentence-transformers/all-MiniLM-L6-v2. */
+    pub model_id: String,
+    pub revision: String,
+    pub dimension: usize,
+    /** Load the model from this directory instead of from Hugging Face, if not empty. */
+    pub model_dir: String,
+    /** Only use the Hugging Face cache, never download. */
+    pub offline: bool,
+}
+
+impl ModelSettings {
+    pub fn from_config(config: &Config) -> ModelSettings {
+        ModelSettings {
+            model_id: config.model_id.clone(),
+            revision: config.model_revision.clone(),
+            dimension: config.embedding_dimension,
+            model_dir: config.model_dir.clone(),
+            offline: config.model_offline,
+        }
+    }
+
+    /**
+     * Embeddings of different models can't be compared, so instances only search each other
+     * when this is the same.
+     */
+    pub fn identity(&self) -> String {
+        model_identity(&self.model_id, &self.revision)
+    }
+}
+
+pub fn model_identity(model_id: &str, revision: &str) -> String {
+    format!("{}@{}", model_id, revision)
+}
+
+/** Identity of instances that don't tell us, they use the model that used to be hard-wired. */
+pub fn default_model_identity() -> String {
+    model_identity(DEFAULT_MODEL_ID, DEFAULT_MODEL_REVISION)
+}
+
+/**
+ * Download (or take from the cache or model_dir) and load the model. Which implementation is
+ * used depends on the model_type in the config.json of the model.
+ */
+pub fn load_model(settings: &ModelSettings, device: &Device) -> Result<Box<dyn EmbeddingModel>> {
+    let files = ModelFiles::get(settings)?;
+    let config = std::fs::read_to_string(&files.config)
+        .with_context(|| format!("Reading {}", files.config.display()))?;
+    let model_type = serde_json::from_str::<serde_json::Value>(&config)?["model_type"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    let model: Box<dyn EmbeddingModel> = match model_type.as_str() {
+        "bert" => Box::new(BertEmbeddingModel::load(&files, &config, device)?),
+        _ => bail!(
+            "Model {} has type '{}', only 'bert' is supported",
+            settings.model_id,
+            model_type
+        ),
+    };
+    if model.dimension() != settings.dimension {
+        bail!(
+            "Model {} produces embeddings of length {}, but embedding_dimension is {}",
+            settings.model_id,
+            model.dimension(),
+            settings.dimension
+        );
+    }
+    Ok(model)
+}
+
+struct ModelFiles {
+    config: PathBuf,
+    tokenizer: PathBuf,
+    weights: PathBuf,
+}
+
+impl ModelFiles {
+    fn get(settings: &ModelSettings) -> Result<ModelFiles> {
+        if !settings.model_dir.is_empty() {
+            return ModelFiles::from_dir(Path::new(&settings.model_dir));
+        }
+
+        let repo = Repo::with_revision(
+            settings.model_id.clone(),
+            RepoType::Model,
+            settings.revision.clone(),
+        );
+        if settings.offline {
+            let cache = Cache::default();
+            let get = |name: &str| {
+                cache.get(&repo, name).ok_or_else(|| {
+                    anyhow!(
+                        "{} of {} is not in the Hugging Face cache at {}. Start once with model_offline = false to download it, or set model_dir",
+                        name,
+                        settings.identity(),
+                        cache.path().display()
+                    )
+                })
+            };
+            Ok(ModelFiles {
+                config: get("config.json")?,
+                tokenizer: get("tokenizer.json")?,
+                weights: get("model.safetensors")?,
+            })
+        } else {
+            let api = Api::new()?;
+            let api = api.repo(repo);
+            let get = |name: &str| {
+                api.get(name).with_context(|| {
+                    format!(
+                        "Downloading {} of {}. Set model_offline or model_dir to run without network access",
+                        name,
+                        settings.identity()
+                    )
+                })
+            };
+            Ok(ModelFiles {
+                config: get("config.json")?,
+                tokenizer: get("tokenizer.json")?,
+                weights: get("model.safetensors")?,
+            })
+        }
+    }
+
+    fn from_dir(dir: &Path) -> Result<ModelFiles> {
+        let get = |name: &str| {
+            let path = dir.join(name);
+            if !path.is_file() {
+                bail!(
+                    "{} not found, model_dir should contain config.json, tokenizer.json and model.safetensors",
+                    path.display()
+                );
+            }
+            Ok(path)
+        };
+        Ok(ModelFiles {
+            config: get("config.json")?,
+            tokenizer: get("tokenizer.json")?,
+            weights: get("model.safetensors")?,
+        })
+    }
+}
+
+/** BERT based sentence-transformers, like all-MiniLM-L6-v2. */
+pub struct BertEmbeddingModel {
+    tokenizer: Tokenizer,
+    model: BertModel,
+    dimension: usize,
+    /** Texts with more tokens than this are split into chunks. */
+    max_tokens: usize,
+    cls_id: u32,
+    sep_id: u32,
+    pad_id: u32,
+}
+
+impl BertEmbeddingModel {
+    fn load(files: &ModelFiles, config: &str, device: &Device) -> Result<BertEmbeddingModel> {
+        let config: model::Config = serde_json::from_str(config)?;
+        let mut tokenizer = Tokenizer::from_file(&files.tokenizer)
+            .map_err(E::msg)
+            .with_context(|| format!("Loading {}", files.tokenizer.display()))?;
+        // We add the special tokens, split long texts and pad the batches ourselves.
+        tokenizer.with_padding(None);
+        tokenizer.with_truncation(None);
+        let special = |token: &str| {
+            tokenizer
+                .token_to_id(token)
+                .ok_or_else(|| anyhow!("{} has no {} token", files.tokenizer.display(), token))
+        };
+        let cls_id = special("[CLS]")?;
+        let sep_id = special("[SEP]")?;
+
+        let weights = unsafe { candle::safetensors::MmapedFile::new(&files.weights)? };
+        let weights = weights.deserialize()?;
+        let vb = VarBuilder::from_safetensors(vec![weights], DTYPE, device);
+        let model = BertModel::load(vb, &config)
+            .with_context(|| format!("Loading {}", files.weights.display()))?;
+        Ok(BertEmbeddingModel {
+            model,
+            tokenizer,
+            dimension: config.hidden_size(),
+            max_tokens: config.max_position_embeddings(),
+            cls_id,
+            sep_id,
+            pad_id: config.pad_token_id() as u32,
+        })
+    }
+
+    /**
+     * Token ids of the texts, with texts that are too long for the model split into several
+     * chunks. Returns the chunks and the index of the text each chunk belongs to.
+     */
+    fn chunks(&self, inputs: &[&str]) -> Result<(Vec<Vec<u32>>, Vec<usize>)> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(inputs.to_vec(), false)
+            .map_err(E::msg)?;
+        let mut chunks = Vec::new();
+        let mut owners = Vec::new();
+        // Room for [CLS] and [SEP].
+        let chunk_len = self.max_tokens - 2;
+        for (i, encoding) in encodings.iter().enumerate() {
+            let ids = encoding.get_ids();
+            // An empty text still gets an embedding, of just the special tokens.
+            let pieces: Vec<&[u32]> = if ids.is_empty() {
+                vec![ids]
+            } else {
+                ids.chunks(chunk_len).collect()
+            };
+            for piece in pieces {
+                let mut chunk = Vec::with_capacity(piece.len() + 2);
+                chunk.push(self.cls_id);
+                chunk.extend_from_slice(piece);
+                chunk.push(self.sep_id);
+                chunks.push(chunk);
+                owners.push(i);
+            }
+        }
+        Ok((chunks, owners))
+    }
+
+    /** Sum of the output vectors of the real tokens (not the padding) of each chunk. */
+    fn token_sums(&self, chunks: &[Vec<u32>]) -> Result<Vec<Vec<f32>>> {
+        let device = &self.model.device;
+        let max_len = chunks.iter().map(|c| c.len()).max().unwrap_or(0);
+        let mut token_ids = Vec::with_capacity(chunks.len() * max_len);
+        let mut attention_mask = Vec::with_capacity(chunks.len() * max_len);
+        for chunk in chunks {
+            let padding = max_len - chunk.len();
+            token_ids.extend_from_slice(chunk);
+            token_ids.extend(repeat(self.pad_id).take(padding));
+            attention_mask.extend(repeat(1u32).take(chunk.len()));
+            attention_mask.extend(repeat(0u32).take(padding));
+        }
+        let token_ids = Tensor::from_vec(token_ids, (chunks.len(), max_len), device)?;
+        let attention_mask = Tensor::from_vec(attention_mask, (chunks.len(), max_len), device)?;
+        let token_type_ids = token_ids.zeros_like()?;
+        let embeddings = self
+            .model
+            .forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
+
+        let mask = attention_mask.to_dtype(DTYPE)?.unsqueeze(2)?;
+        Ok(embeddings.broadcast_mul(&mask)?.sum(1)?.to_vec2()?)
+    }
+}
+
+impl EmbeddingModel for BertEmbeddingModel {
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /**
+     * Mean pooling over all tokens of a text, like sentence-transformers does. For texts that
+     * were split into chunks that is the mean over the tokens of all chunks.
+     */
+    fn embed(&self, inputs: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let (chunks, owners) = self.chunks(inputs)?;
+        let sums = self.token_sums(&chunks)?;
+
+        // The embeddings are normalized, so we don't have to divide the sums by the token count.
+        let mut results = vec![vec![0.0f32; self.dimension]; inputs.len()];
+        for (sum, owner) in zip(sums, owners) {
+            for (r, s) in results[owner].iter_mut().zip(sum) {
+                *r += s;
+            }
+        }
+        for result in results.iter_mut() {
+            normalize(result);
+        }
+        Ok(results)
+    }
+}
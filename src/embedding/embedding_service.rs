@@ -18,19 +18,17 @@
    along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::{sync::mpsc::Receiver, time::Instant};
-
-use crate::{
-    embedding::model::{BertModel, Config, DTYPE},
-    search::vector::normalize,
-};
-use anyhow::{anyhow, Error as E, Result};
-use candle::Tensor;
-use candle_nn::VarBuilder;
-use hf_hub::{api::sync::Api, Cache, Repo, RepoType};
-use tokenizers::{PaddingParams, Tokenizer};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::embedding::embedding_model::{load_model, EmbeddingModel, ModelSettings};
+use anyhow::Result;
 
 use candle::Device;
+use tokio::sync::oneshot;
+use tokio::sync::oneshot::error::RecvError;
 
 pub fn device(cpu: bool) -> Result<Device> {
     if cpu {
@@ -46,123 +44,287 @@ pub fn device(cpu: bool) -> Result<Device> {
     }
 }
 
-struct EmbeddingProvider {
-    tokenizer: Tokenizer,
-    model: BertModel,
-}
-
-impl EmbeddingProvider {
-    fn new() -> anyhow::Result<EmbeddingProvider> {
-        let cpu = false;
-        let offline = false;
-
-        let device = device(cpu)?;
-        let model_id = "sentence-transformers/all-MiniLM-L6-v2".to_string();
-        let revision = "refs/pr/21".to_string();
-
-        let repo = Repo::with_revision(model_id, RepoType::Model, revision);
-        let (config_filename, tokenizer_filename, weights_filename) = if offline {
-            let cache = Cache::default();
-            (
-                cache
-                    .get(&repo, "config.json")
-                    .ok_or(anyhow!("Missing config file in cache"))?,
-                cache
-                    .get(&repo, "tokenizer.json")
-                    .ok_or(anyhow!("Missing tokenizer file in cache"))?,
-                cache
-                    .get(&repo, "model.safetensors")
-                    .ok_or(anyhow!("Missing weights file in cache"))?,
-            )
-        } else {
-            let api = Api::new()?;
-            let api = api.repo(repo);
-            (
-                api.get("config.json")?,
-                api.get("tokenizer.json")?,
-                api.get("model.safetensors")?,
-            )
-        };
-        let config = std::fs::read_to_string(config_filename)?;
-        let config: Config = serde_json::from_str(&config)?;
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-
-        let weights = unsafe { candle::safetensors::MmapedFile::new(weights_filename)? };
-        let weights = weights.deserialize()?;
-        let vb = VarBuilder::from_safetensors(vec![weights], DTYPE, &device);
-        let model = BertModel::load(vb, &config)?;
-        Ok(EmbeddingProvider { model, tokenizer })
-    }
+/** Load the configured model on the GPU if we have one. */
+pub fn load_embedding_model(settings: &ModelSettings) -> Result<Arc<dyn EmbeddingModel>> {
+    let cpu = false;
+    let device = device(cpu)?;
+    let model: Arc<dyn EmbeddingModel> = load_model(settings, &device)?.into();
+    println!(
+        "[Embedding] Loaded {} ({} dimensions)",
+        settings.identity(),
+        model.dimension()
+    );
+    Ok(model)
+}
 
-    pub fn calculate_embedding(&mut self, inputs: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
-        let device = &self.model.device;
+#[derive(Debug)]
+pub enum EmbeddingError {
+    /** The model could not calculate the embeddings. */
+    Model(String),
+    /** The embedding service went away before it replied. */
+    Stopped,
+}
 
-        let n_sentences = inputs.len();
-        if let Some(pp) = self.tokenizer.get_padding_mut() {
-            pp.strategy = tokenizers::PaddingStrategy::BatchLongest
-        } else {
-            let pp = PaddingParams {
-                strategy: tokenizers::PaddingStrategy::BatchLongest,
-                ..Default::default()
-            };
-            self.tokenizer.with_padding(Some(pp));
-        }
-        let tokens = self
-            .tokenizer
-            .encode_batch(inputs.to_vec(), true)
-            .map_err(E::msg)?;
-        let token_ids = tokens
-            .iter()
-            .map(|tokens| {
-                let tokens = tokens.get_ids().to_vec();
-                Ok(Tensor::new(tokens.as_slice(), device)?)
-            })
-            .collect::<Result<Vec<_>>>()?;
-
-        let token_ids = Tensor::stack(&token_ids, 0)?;
-        let token_type_ids = token_ids.zeros_like()?;
-        let embeddings = self.model.forward(&token_ids, &token_type_ids)?;
-
-        // Apply some avg-pooling by taking the mean embedding value for all tokens (including padding)
-        let (_n_sentence, n_tokens, _hidden_size) = embeddings.dims3()?;
-        let embeddings = (embeddings.sum(1)? / (n_tokens as f64))?;
-
-        let mut results: Vec<Vec<f32>> = Vec::new();
-        for j in 0..n_sentences {
-            let e_j = embeddings.get(j)?;
-            let mut emb: Vec<f32> = e_j.to_vec1()?;
-            normalize(&mut emb);
-            results.push(emb);
+impl fmt::Display for EmbeddingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmbeddingError::Model(e) => write!(f, "Calculating embeddings failed: {}", e),
+            EmbeddingError::Stopped => write!(f, "The embedding service stopped"),
         }
+    }
+}
+
+impl std::error::Error for EmbeddingError {}
 
-        Ok(results)
+impl From<RecvError> for EmbeddingError {
+    fn from(_: RecvError) -> Self {
+        EmbeddingError::Stopped
     }
 }
 
 pub enum EmbeddingMsg {
     GetEmbedding {
         text: String,
-        otx: tokio::sync::oneshot::Sender<Vec<f32>>,
+        otx: oneshot::Sender<Result<Vec<f32>, EmbeddingError>>,
     },
+    /** The embeddings of several texts, in the same order as the texts. */
+    GetEmbeddings {
+        texts: Vec<String>,
+        otx: oneshot::Sender<Result<Vec<Vec<f32>>, EmbeddingError>>,
+    },
+}
+
+impl EmbeddingMsg {
+    fn texts(&self) -> Vec<&str> {
+        match self {
+            EmbeddingMsg::GetEmbedding { text, .. } => vec![text.as_str()],
+            EmbeddingMsg::GetEmbeddings { texts, .. } => texts.iter().map(|t| t.as_str()).collect(),
+        }
+    }
+}
+
+/** The queues of an embedding channel, shared by the senders and the workers. */
+struct Lanes {
+    /** Searches from users, they go ahead of pages that are being indexed. */
+    priority: VecDeque<EmbeddingMsg>,
+    bulk: VecDeque<EmbeddingMsg>,
+    senders: usize,
+}
+
+struct Shared {
+    lanes: Mutex<Lanes>,
+    /** Signalled when a message arrives or the last sender goes away. */
+    available: Condvar,
+    /** Signalled when there is room in the bulk lane again. */
+    space: Condvar,
+    /** Sending to the bulk lane blocks when it has this many messages. */
+    bound: usize,
+}
+
+/**
+ * Like a sync_channel, but with a second, unbounded lane for messages that should be handled
+ * first, and a receiver that can be shared by several workers.
+ */
+pub fn embedding_channel(bound: usize) -> (EmbeddingSender, EmbeddingReceiver) {
+    let shared = Arc::new(Shared {
+        lanes: Mutex::new(Lanes {
+            priority: VecDeque::new(),
+            bulk: VecDeque::new(),
+            senders: 1,
+        }),
+        available: Condvar::new(),
+        space: Condvar::new(),
+        bound: bound.max(1),
+    });
+    (
+        EmbeddingSender {
+            shared: shared.clone(),
+        },
+        EmbeddingReceiver { shared },
+    )
+}
+
+pub struct EmbeddingSender {
+    shared: Arc<Shared>,
+}
+
+impl EmbeddingSender {
+    /** Queue a message behind the other work, blocks while the bulk lane is full. */
+    pub fn send(&self, message: EmbeddingMsg) {
+        let mut lanes = self.shared.lanes.lock().unwrap();
+        while lanes.bulk.len() >= self.shared.bound {
+            lanes = self.shared.space.wait(lanes).unwrap();
+        }
+        lanes.bulk.push_back(message);
+        // Wake all workers, one may be waiting for a message of the other lane.
+        self.shared.available.notify_all();
+    }
+
+    /** Queue a message ahead of all messages sent with send(). */
+    pub fn send_priority(&self, message: EmbeddingMsg) {
+        let mut lanes = self.shared.lanes.lock().unwrap();
+        lanes.priority.push_back(message);
+        self.shared.available.notify_all();
+    }
+}
+
+impl Clone for EmbeddingSender {
+    fn clone(&self) -> Self {
+        self.shared.lanes.lock().unwrap().senders += 1;
+        EmbeddingSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for EmbeddingSender {
+    fn drop(&mut self) {
+        let mut lanes = self.shared.lanes.lock().unwrap();
+        lanes.senders -= 1;
+        if lanes.senders == 0 {
+            self.shared.available.notify_all();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EmbeddingReceiver {
+    shared: Arc<Shared>,
+}
+
+impl EmbeddingReceiver {
+    /**
+     * Wait for messages and return them as a batch of at most 'batch_size' texts. After the
+     * first message we wait at most 'latency' for more. A batch only contains messages of
+     * one lane, so a search is never held up by the pages of a bulk batch.
+     * Returns None when all senders are gone and everything has been received.
+     */
+    fn recv_batch(&self, batch_size: usize, latency: Duration) -> Option<Vec<EmbeddingMsg>> {
+        let mut lanes = self.shared.lanes.lock().unwrap();
+        while lanes.priority.is_empty() && lanes.bulk.is_empty() {
+            if lanes.senders == 0 {
+                return None;
+            }
+            lanes = self.shared.available.wait(lanes).unwrap();
+        }
+        let priority = !lanes.priority.is_empty();
+        let deadline = Instant::now() + latency;
+        let mut batch = Vec::new();
+        let mut text_count = 0;
+        while text_count < batch_size {
+            let lane = if priority {
+                &mut lanes.priority
+            } else {
+                &mut lanes.bulk
+            };
+            if let Some(message) = lane.pop_front() {
+                text_count += message.texts().len();
+                batch.push(message);
+                if !priority {
+                    self.shared.space.notify_one();
+                }
+                continue;
+            }
+            let now = Instant::now();
+            if now >= deadline || lanes.senders == 0 {
+                break;
+            }
+            lanes = self
+                .shared
+                .available
+                .wait_timeout(lanes, deadline - now)
+                .unwrap()
+                .0;
+            // Don't keep a search waiting while we collect a bulk batch.
+            if !priority && !lanes.priority.is_empty() {
+                break;
+            }
+        }
+        Some(batch)
+    }
 }
 
+/**
+ * Calculates embeddings. Requests that are queued up are combined into a single batch, which
+ * is a lot faster than calculating them one by one. Several services can share a receiver
+ * and a model, each runs on its own thread.
+ */
 pub struct EmbeddingService {
-    pub embedding_rx: Receiver<EmbeddingMsg>,
+    pub embedding_rx: EmbeddingReceiver,
+    pub model: Arc<dyn EmbeddingModel>,
+    /** Maximum number of texts in a batch. */
+    pub batch_size: usize,
+    /** How long to wait for more requests after the first one. */
+    pub batch_latency: Duration,
+    pub debug: usize,
 }
 
 impl EmbeddingService {
     pub fn start(&mut self) {
-        let mut embedding_provider = EmbeddingProvider::new().unwrap();
+        while let Some(batch) = self
+            .embedding_rx
+            .recv_batch(self.batch_size, self.batch_latency)
+        {
+            Self::calculate_batch(self.model.as_ref(), batch, self.debug);
+        }
+    }
+
+    fn calculate_batch(model: &dyn EmbeddingModel, batch: Vec<EmbeddingMsg>, debug: usize) {
+        let texts: Vec<&str> = batch.iter().flat_map(|m| m.texts()).collect();
+        let start = Instant::now();
+        let result = if texts.is_empty() {
+            Ok(Vec::new())
+        } else {
+            model.embed(&texts).map_err(|e| format!("{:#}", e))
+        };
+        // Otherwise the embeddings would end up with the wrong texts.
+        let result = result.and_then(|embeddings| {
+            if embeddings.len() == texts.len() {
+                Ok(embeddings)
+            } else {
+                Err(format!(
+                    "The model returned {} embeddings for {} texts",
+                    embeddings.len(),
+                    texts.len()
+                ))
+            }
+        });
+        let mut embeddings = match result {
+            Ok(embeddings) => {
+                if debug > 0 {
+                    println!(
+                        "[Embedding] Calculated {} in {:?}",
+                        texts.len(),
+                        start.elapsed()
+                    );
+                }
+                embeddings.into_iter()
+            }
+            Err(error) => {
+                eprintln!("[Embedding] Failed to calculate {}: {}", texts.len(), error);
+                for message in batch {
+                    match message {
+                        EmbeddingMsg::GetEmbedding { otx, .. } => {
+                            let _ = otx.send(Err(EmbeddingError::Model(error.clone())));
+                        }
+                        EmbeddingMsg::GetEmbeddings { otx, .. } => {
+                            let _ = otx.send(Err(EmbeddingError::Model(error.clone())));
+                        }
+                    }
+                }
+                return;
+            }
+        };
 
-        while let Ok(message) = self.embedding_rx.recv() {
+        // A requester that went away no longer needs its result, so send errors are ignored.
+        for message in batch {
             match message {
-                EmbeddingMsg::GetEmbedding { text, otx } => {
-                    let start = Instant::now();
-                    let mut r = embedding_provider
-                        .calculate_embedding(&[text.as_str()])
-                        .unwrap();
-                    println!("[Embedding] Calculated in {:?}", start.elapsed());
-                    otx.send(r.remove(0)).unwrap();
+                EmbeddingMsg::GetEmbedding { otx, .. } => {
+                    // There is one for every text, see above.
+                    let _ = otx.send(Ok(embeddings.next().unwrap_or_default()));
+                }
+                EmbeddingMsg::GetEmbeddings { texts, otx } => {
+                    let _ = otx.send(Ok(embeddings.by_ref().take(texts.len()).collect()));
                 }
             }
         }
@@ -1,2 +1,3 @@
+pub mod embedding_model;
 pub mod embedding_service;
 pub mod model;
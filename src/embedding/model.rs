@@ -157,6 +157,18 @@ impl Default for Config {
 }
 
 impl Config {
+    pub fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    pub fn max_position_embeddings(&self) -> usize {
+        self.max_position_embeddings
+    }
+
+    pub fn pad_token_id(&self) -> usize {
+        self.pad_token_id
+    }
+
     fn _all_mini_lm_l6_v2() -> Self {
         // https://huggingface.co/sentence-transformers/all-MiniLM-L6-v2/blob/main/config.json
         Self {
@@ -322,7 +334,7 @@ impl BertSelfAttention {
         xs.contiguous()
     }
 
-    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+    fn forward(&self, hidden_states: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
         let query_layer = self.query.forward(hidden_states)?;
         let key_layer = self.key.forward(hidden_states)?;
@@ -333,7 +345,10 @@ impl BertSelfAttention {
         let value_layer = self.transpose_for_scores(&value_layer)?;
 
         let attention_scores = query_layer.matmul(&key_layer.t()?)?;
-        let attention_scores = (attention_scores / (self.attention_head_size as f64).sqrt())?;
+        let mut attention_scores = (attention_scores / (self.attention_head_size as f64).sqrt())?;
+        if let Some(attention_mask) = attention_mask {
+            attention_scores = attention_scores.broadcast_add(attention_mask)?;
+        }
         let attention_probs = {
             let _enter_sm = self.span_softmax.enter();
             candle_nn::ops::softmax(&attention_scores, candle::D::Minus1)?
@@ -397,9 +412,9 @@ impl BertAttention {
         })
     }
 
-    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+    fn forward(&self, hidden_states: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
-        let self_outputs = self.self_attention.forward(hidden_states)?;
+        let self_outputs = self.self_attention.forward(hidden_states, attention_mask)?;
         let attention_output = self.self_output.forward(&self_outputs, hidden_states)?;
         Ok(attention_output)
     }
@@ -484,9 +499,9 @@ impl BertLayer {
         })
     }
 
-    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+    fn forward(&self, hidden_states: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
-        let attention_output = self.attention.forward(hidden_states)?;
+        let attention_output = self.attention.forward(hidden_states, attention_mask)?;
         // TODO: Support cross-attention?
         // https://github.com/huggingface/transformers/blob/6eedfa6dd15dc1e22a55ae036f681914e5a0d9a1/src/transformers/models/bert/modeling_bert.py#L523
         // TODO: Support something similar to `apply_chunking_to_forward`?
@@ -513,12 +528,12 @@ impl BertEncoder {
         Ok(BertEncoder { layers, span })
     }
 
-    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+    fn forward(&self, hidden_states: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
         let mut hidden_states = hidden_states.clone();
         // Use a loop rather than a fold as it's easier to modify when adding debug/...
         for layer in self.layers.iter() {
-            hidden_states = layer.forward(&hidden_states)?
+            hidden_states = layer.forward(&hidden_states, attention_mask)?
         }
         Ok(hidden_states)
     }
@@ -562,10 +577,32 @@ impl BertModel {
         })
     }
 
-    pub fn forward(&self, input_ids: &Tensor, token_type_ids: &Tensor) -> Result<Tensor> {
+    /**
+     * The attention mask has shape (batch, sequence), with 1 for real tokens and 0 for padding.
+     * Without it padding tokens are attended to, which changes the output of the other tokens.
+     */
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
         let _enter = self.span.enter();
         let embedding_output = self.embeddings.forward(input_ids, token_type_ids)?;
-        let sequence_output = self.encoder.forward(&embedding_output)?;
+        // Turn the mask into something we can add to the attention scores, with shape
+        // (batch, 1, 1, sequence): 0 for real tokens and a large negative number for padding.
+        let attention_mask = match attention_mask {
+            Some(mask) => Some(
+                mask.to_dtype(DTYPE)?
+                    .unsqueeze(1)?
+                    .unsqueeze(1)?
+                    .affine(10000.0, -10000.0)?,
+            ),
+            None => None,
+        };
+        let sequence_output = self
+            .encoder
+            .forward(&embedding_output, attention_mask.as_ref())?;
         Ok(sequence_output)
     }
 }
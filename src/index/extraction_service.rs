@@ -17,6 +17,7 @@
    along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use anyhow::ensure;
 use flate2::read::MultiGzDecoder;
 use rand::Rng;
 use std::{
@@ -32,25 +33,28 @@ const WARC_FILE_LIST: &str =
     "https://data.commoncrawl.org/crawl-data/CC-MAIN-2023-23/warc.paths.gz";
 
 /**
- * Download random WARC files from the WARC_FILE_LIST and extract pages from them.
- * The pages will be sent to 'sender' for indexing.
+ * Download random WARC files from the WARC_FILE_LIST and extract pages from them, keeping at
+ * most 'max_text_len' bytes of their text. The pages will be sent to 'sender' for indexing.
  *
  * This function will run forever.
  */
-pub async fn start_extraction_service(sender: SyncSender<SearchMsg>) -> anyhow::Result<()> {
-    let files = tokio::task::spawn_blocking(move || {
-        let response = reqwest::blocking::get(WARC_FILE_LIST).unwrap();
+pub async fn start_extraction_service(
+    sender: SyncSender<SearchMsg>,
+    max_text_len: usize,
+) -> anyhow::Result<()> {
+    let files = tokio::task::spawn_blocking(move || -> anyhow::Result<Vec<String>> {
+        let response = reqwest::blocking::get(WARC_FILE_LIST)?;
         let file_list_reader = BufReader::new(MultiGzDecoder::new(response));
-        file_list_reader
+        Ok(file_list_reader
             .lines()
-            .map(|x| x)
-            .collect::<Result<Vec<String>, _>>()
+            .collect::<Result<Vec<String>, _>>()?)
     })
     .await??;
+    ensure!(!files.is_empty(), "No WARC files in {}", WARC_FILE_LIST);
 
     loop {
         let random_file: &str = &files[rand::thread_rng().gen_range(0..files.len())];
-        if let Err(e) = extract_file(sender.clone(), random_file).await {
+        if let Err(e) = extract_file(sender.clone(), random_file, max_text_len).await {
             eprintln!("Error processing {}: {}", random_file, e);
             // Sleep so we don't get rate limited by performing too many requests.
             tokio::time::sleep(Duration::from_secs(60)).await;
@@ -66,6 +70,7 @@ pub async fn start_extraction_service(sender: SyncSender<SearchMsg>) -> anyhow::
 async fn extract_file(
     sender: SyncSender<SearchMsg>,
     random_file: &str,
+    max_text_len: usize,
 ) -> Result<(), anyhow::Error> {
     println!("Indexing {}", random_file);
 
@@ -89,7 +94,7 @@ async fn extract_file(
             tokio::task::spawn_blocking(move || -> Result<(), anyhow::Error> {
                 let response = tokio_util::io::SyncIoBridge::new(response_async);
 
-                let mut page_source = PageSource::read_warc_gz(response);
+                let mut page_source = PageSource::read_warc_gz(response, max_text_len);
 
                 while let Some(page) = page_source.next()? {
                     sender.send(SearchMsg::ExtractedPage {
@@ -108,7 +113,7 @@ async fn extract_file(
         tokio::task::spawn_blocking(move || -> Result<(), anyhow::Error> {
             let response = reqwest::blocking::get(url_string)?;
 
-            let mut page_source = PageSource::read_warc_gz(response);
+            let mut page_source = PageSource::read_warc_gz(response, max_text_len);
 
             while let Some(page) = page_source.next()? {
                 sender.send(SearchMsg::ExtractedPage {
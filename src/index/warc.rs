@@ -17,10 +17,8 @@
    along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::embedding::embedding_service::EmbeddingMsg;
-use crate::search::page_source::PageSource;
-use crate::search::vector::Embedding;
-use crate::util::any_as_u8_slice;
+use crate::embedding::embedding_service::{EmbeddingError, EmbeddingMsg, EmbeddingSender};
+use crate::search::page_source::{PageSource, PAGE_START_LEN};
 use crate::util::default_progress_bar;
 use std;
 use std::fs::File;
@@ -28,26 +26,39 @@ use std::io;
 use std::io::Read;
 use std::io::Write;
 use std::path::PathBuf;
-use std::sync::mpsc::SyncSender;
 use std::time::Instant;
 use tokio::sync::oneshot;
 
 #[derive(Debug)]
-#[repr(C)]
 pub struct PageEntry {
     pub url_pos: u64,
     pub title_pos: u64,
-    pub vector: Embedding<f32>,
+    pub vector: Vec<f32>,
     pub url_len: u64,
     pub title_len: u64,
 }
 
+impl PageEntry {
+    /** Same layout as the #[repr(C)] struct this used to be, when the vector had a fixed size. */
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.url_pos.to_ne_bytes())?;
+        writer.write_all(&self.title_pos.to_ne_bytes())?;
+        for v in &self.vector {
+            writer.write_all(&v.to_ne_bytes())?;
+        }
+        writer.write_all(&self.url_len.to_ne_bytes())?;
+        writer.write_all(&self.title_len.to_ne_bytes())?;
+        Ok(())
+    }
+}
+
 pub fn extract_records_and_add_to_index<T: Read>(
     input: &mut T,
     filename: &PathBuf,
-    embedding_tx: SyncSender<EmbeddingMsg>,
+    embedding_tx: EmbeddingSender,
 ) -> io::Result<()> {
-    let mut page_source = PageSource::read_warc_gz(input);
+    // Only the start of the text is embedded here.
+    let mut page_source = PageSource::read_warc_gz(input, PAGE_START_LEN);
 
     let mut output_file_name = filename.clone();
     output_file_name.set_extension("emb");
@@ -77,13 +88,15 @@ pub fn extract_records_and_add_to_index<T: Read>(
         progress.set_position(count);
 
         let (otx2, orx2) = oneshot::channel();
-        embedding_tx
-            .send(EmbeddingMsg::GetEmbedding {
-                text: record.combined,
-                otx: otx2,
-            })
-            .unwrap();
-        let embedding = orx2.blocking_recv().unwrap();
+        embedding_tx.send(EmbeddingMsg::GetEmbedding {
+            text: record.combined,
+            otx: otx2,
+        });
+        let embedding = orx2
+            .blocking_recv()
+            .map_err(EmbeddingError::from)
+            .and_then(|embedding| embedding)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
         let url_len = record.url.len() as u64;
         let title_len = record.title.len() as u64;
@@ -91,12 +104,11 @@ pub fn extract_records_and_add_to_index<T: Read>(
         let entry = PageEntry {
             url_pos,
             title_pos,
-            vector: embedding.as_slice().try_into().unwrap(),
+            vector: embedding,
             url_len,
             title_len,
         };
-        let bytes: &[u8] = unsafe { any_as_u8_slice(&entry) };
-        output_writer.write_all(bytes)?;
+        entry.write_to(&mut output_writer)?;
 
         url_writer.write_all(&record.url.as_bytes())?;
         url_pos += url_len as u64;
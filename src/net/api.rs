@@ -0,0 +1,240 @@
+/*
+   Copyright 2023 Krol Inventions B.V.
+
+   This file is part of DawnSearch.
+
+   DawnSearch is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   DawnSearch is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::mpsc::SyncSender;
+use std::time::Instant;
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::search::search_msg::SearchMsg::*;
+use crate::search::search_msg::{
+    page_offset, Reply, SearchError, SearchMsg, DEFAULT_RESULTS, MAX_RESULTS,
+};
+use crate::search::search_provider::SearchResult;
+
+use super::http_service::{param, parse_page_reference};
+
+/**
+ * The JSON API, for tools that would otherwise have to scrape the HTML:
+ *
+ * - /api/search?q=cats - pages matching the query, operators like site: work.
+ * - /api/like?s=instance:page - pages like the given one, the instance is empty for ours.
+ * - /api/stats - the number of pages we have indexed and how well the cache works.
+ * - /api/embedding?q=cats or ?s=instance:page - the embedding of a query or a page.
+ *
+ * Searches take 'limit' (at most MAX_RESULTS) and 'page', counting from 1. Errors are
+ * returned as {"error": "..."} with a fitting status code.
+ */
+pub struct ApiResponse {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl IntoResponse for ApiResponse {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            [(header::CONTENT_TYPE, "application/json")],
+            self.body,
+        )
+            .into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct SearchResponse<'a> {
+    #[serde(flatten)]
+    result: &'a SearchResult,
+    /** In seconds. */
+    elapsed: f32,
+}
+
+#[derive(Serialize)]
+struct EmbeddingResponse<'a> {
+    embedding: &'a [f32],
+}
+
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    error: &'a str,
+}
+
+/** Handle a request for 'endpoint', which is the path without /api/. */
+pub async fn handle_api(
+    endpoint: &str,
+    params: &[(String, String)],
+    tx: &SyncSender<SearchMsg>,
+) -> ApiResponse {
+    let param = |name: &str| param(params, name);
+    let result = match endpoint {
+        "search" | "like" => {
+            let (limit, offset) = match paging(param("limit"), param("page")) {
+                Ok(p) => p,
+                Err(e) => return error(status_of(&e), &e.to_string()),
+            };
+            let start = Instant::now();
+            let result = if endpoint == "search" {
+                let Some(query) = param("q").filter(|q| !q.trim().is_empty()) else {
+                    return bad_request("Missing parameter q");
+                };
+                request(tx, |otx| TextSearch {
+                    otx,
+                    query: query.to_string(),
+                    limit,
+                    offset,
+                })
+                .await
+            } else {
+                let Some((instance_id, page_id)) = param("s").and_then(parse_page_reference) else {
+                    return bad_request("Parameter s must look like instance:page");
+                };
+                request(tx, |otx| MoreLikeSearch {
+                    otx,
+                    instance_id,
+                    page_id,
+                    limit,
+                    offset,
+                })
+                .await
+            };
+            result.map(|result| {
+                json(&SearchResponse {
+                    result: &result,
+                    elapsed: start.elapsed().as_secs_f32(),
+                })
+            })
+        }
+        "stats" => request(tx, |otx| Stats { otx })
+            .await
+            .map(|stats| json(&stats)),
+        "embedding" => {
+            let result = if let Some(query) = param("q") {
+                request(tx, |otx| QueryEmbedding {
+                    otx,
+                    query: query.to_string(),
+                })
+                .await
+            } else if let Some((instance_id, page_id)) = param("s").and_then(parse_page_reference) {
+                request(tx, |otx| PageEmbedding {
+                    otx,
+                    instance_id,
+                    page_id,
+                })
+                .await
+            } else {
+                return bad_request("Either q or s=instance:page is needed");
+            };
+            result.map(|embedding| {
+                json(&EmbeddingResponse {
+                    embedding: &embedding,
+                })
+            })
+        }
+        _ => return error(StatusCode::NOT_FOUND, "Unknown endpoint"),
+    };
+    match result {
+        Ok(body) => ApiResponse {
+            status: StatusCode::OK,
+            body,
+        },
+        Err(e) => {
+            eprintln!("[HTTP] API request failed: {}", e);
+            error(status_of(&e), &e.to_string())
+        }
+    }
+}
+
+/** Send a message to the search service and wait for its reply. */
+pub async fn request<T>(
+    tx: &SyncSender<SearchMsg>,
+    message: impl FnOnce(Reply<T>) -> SearchMsg,
+) -> Result<T, SearchError> {
+    let (otx, orx) = oneshot::channel();
+    send(tx, message(otx)).await?;
+    orx.await?
+}
+
+/**
+ * Send a message to the search service. Its queue is full most of the time while we index,
+ * so we wait for room on a blocking thread instead of on a thread of the runtime.
+ */
+pub async fn send(tx: &SyncSender<SearchMsg>, message: SearchMsg) -> Result<(), SearchError> {
+    let tx = tx.clone();
+    tokio::task::spawn_blocking(move || tx.send(message))
+        .await
+        .map_err(|_| SearchError::Stopped)??;
+    Ok(())
+}
+
+/** The limit and offset of the results to return, the offset is below MAX_RESULTS. */
+fn paging(limit: Option<&str>, page: Option<&str>) -> Result<(usize, usize), SearchError> {
+    let limit = match limit {
+        Some(l) => l
+            .parse()
+            .ok()
+            .filter(|l| (1..=MAX_RESULTS).contains(l))
+            .ok_or_else(|| {
+                SearchError::InvalidRequest(format!(
+                    "Parameter limit must be between 1 and {}",
+                    MAX_RESULTS
+                ))
+            })?,
+        None => DEFAULT_RESULTS,
+    };
+    let page: usize = match page {
+        Some(p) => p.parse().unwrap_or(0),
+        None => 1,
+    };
+    let offset = page_offset(page, limit).ok_or_else(|| {
+        SearchError::InvalidRequest(format!(
+            "Parameter page must be a number from 1 to {} for this limit",
+            MAX_RESULTS.div_ceil(limit)
+        ))
+    })?;
+    Ok((limit, offset))
+}
+
+pub fn status_of(e: &SearchError) -> StatusCode {
+    match e {
+        SearchError::PageNotFound(_) => StatusCode::NOT_FOUND,
+        SearchError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        SearchError::Network(_) => StatusCode::BAD_GATEWAY,
+        SearchError::Stopped => StatusCode::SERVICE_UNAVAILABLE,
+        SearchError::Embedding(_) | SearchError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> String {
+    // Our types always serialize.
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+fn bad_request(message: &str) -> ApiResponse {
+    error(StatusCode::BAD_REQUEST, message)
+}
+
+fn error(status: StatusCode, message: &str) -> ApiResponse {
+    ApiResponse {
+        status,
+        body: json(&ErrorResponse { error: message }),
+    }
+}
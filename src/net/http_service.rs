@@ -18,164 +18,352 @@
 */
 
 use crate::config::Config;
-use crate::net::web::{format_results, main_page, results_page};
-use crate::search::search_msg::SearchMsg;
+use crate::net::api::{handle_api, request, send, status_of};
+use crate::net::web::{
+    atom_feed, feed_link, feed_time, format_error, format_results, format_topics, main_page,
+    opensearch_description, pagination, results_page, topics_link,
+};
 use crate::search::search_msg::SearchMsg::*;
+use crate::search::search_msg::{
+    page_offset, SearchError, SearchMsg, DEFAULT_RESULTS, MAX_RESULTS,
+};
+use crate::search::search_provider::SearchResult;
+use crate::search::topics::{Topic, TOPIC_REFERENCES};
+use axum::extract::{Form, Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use std::cmp::Reverse;
 use std::sync::mpsc::SyncSender;
-use std::time::Instant;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpListener;
+use std::time::{Duration, Instant};
 use tokio::sync::oneshot;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::compression::CompressionLayer;
+use tower_http::timeout::TimeoutLayer;
+
+/** Number of pages in a feed. */
+const FEED_ENTRIES: usize = 50;
+
+/** Grouping by topic needs more results than fit on a page to find the smaller topics. */
+const TOPIC_CANDIDATES: usize = MAX_RESULTS / 2;
+
+/** What the handlers need, cloned for every request. */
+#[derive(Clone)]
+struct HttpState {
+    tx: SyncSender<SearchMsg>,
+    config: Config,
+}
+
+/**
+ * Query parameters in the order they were given, already decoded. For POST requests
+ * they come from the form in the body.
+ */
+type Params = Form<Vec<(String, String)>>;
 
 pub async fn start_http_service(tx2: SyncSender<SearchMsg>, config: Config) -> anyhow::Result<()> {
-    // Next up we create a TCP listener which will listen for incoming
-    // connections. This TCP listener is bound to the address we determined
-    // above and must be associated with an event loop.
-    let listener = TcpListener::bind(&config.web_listen_address).await.unwrap();
+    let listener = std::net::TcpListener::bind(&config.web_listen_address)?;
+    listener.set_nonblocking(true)?;
     println!("[Web] Listening on: {}", &config.web_listen_address);
 
-    loop {
-        // Asynchronously wait for an inbound socket.
-        let (socket, _) = match listener.accept().await {
-            Ok(x) => x,
-            Err(e) => {
-                eprintln!("Error on accept {:?}", e);
-                continue;
-            }
-        };
-
-        // And this is where much of the magic of this server happens. We
-        // crucially want all clients to make progress concurrently, rather than
-        // blocking one on completion of another. To achieve this we use the
-        // `tokio::spawn` function to execute the work in the background.
-        //
-        // Essentially here we're executing a new task to run concurrently,
-        // which will allow all of our clients to be processed concurrently.
-
-        let tx = tx2.clone();
-
-        tokio::spawn(async move {
-            let mut socket = BufReader::new(socket);
-
-            let mut request = String::new();
-            socket.read_line(&mut request).await.unwrap();
-
-            let mut parts = request.split(" ");
-            let method = match parts.next() {
-                Some(s) => s,
-                None => return,
-            };
-            if method != "GET" {
-                return;
-            }
-            let url = match parts.next() {
-                Some(s) => s,
-                None => return,
-            };
-            let mut path_query = url.split("?");
-            let path = match path_query.next() {
-                // ../
-                Some(s) => s,
-                None => return,
-            };
-            let kv = if let Some(query) = path_query.next() {
-                let mut key_value = query.split("=");
-                let key = match key_value.next() {
-                    // q
-                    Some(s) => s,
-                    None => return,
-                };
-                let value = match key_value.next() {
-                    // ...
-                    Some(s) => s,
-                    None => return,
-                };
-                Some((key, value))
-            } else {
-                None
-            };
-
-            if config.debug > 0 {
-                println!("[HTTP] Request for {}", path);
-            }
+    axum::Server::from_tcp(listener)?
+        .serve(router(tx2, config).into_make_service())
+        .await?;
+    Ok(())
+}
 
-            if path == "/robots.txt" {
-                socket
-                    .write_all(
-                        "HTTP/1.1 200 OK\r\n\r\nUser-agent: *\r\nDisallow: /?\r\n".as_bytes(),
-                    )
-                    .await
-                    .unwrap();
-                return;
-            }
+/** All pages we serve, separate from the server so it can be tested without a socket. */
+pub fn router(tx2: SyncSender<SearchMsg>, config: Config) -> Router {
+    let timeout = Duration::from_secs(config.web_timeout_secs);
+    // GET routes also answer HEAD requests.
+    Router::new()
+        .route("/", get(index).post(index))
+        .route("/robots.txt", get(robots))
+        .route("/opensearch.xml", get(opensearch))
+        .route("/suggest", get(suggest))
+        .route("/feed", get(feed))
+        .route("/api/:endpoint", get(api).post(api))
+        .fallback(not_found)
+        .with_state(HttpState { tx: tx2, config })
+        .layer(CompressionLayer::new().gzip(true))
+        .layer(TimeoutLayer::new(timeout))
+        // A bug in a handler gets a 500 instead of a closed connection.
+        .layer(CatchPanicLayer::new())
+}
 
-            if path != "/" {
-                socket
-                    .write_all("HTTP/1.1 404 Not Found\r\n\r\n".as_bytes())
-                    .await
-                    .unwrap();
-                return;
-            }
+async fn robots() -> &'static str {
+    "User-agent: *\r\nDisallow: /?\r\nDisallow: /api/\r\nDisallow: /suggest\r\nDisallow: /feed\r\n"
+}
 
-            let mut line = String::new();
-            while socket.read_line(&mut line).await.is_ok() {
-                if line == "\r\n" {
-                    break; // Found the empty line signaling the end of the headers.
-                }
-                line.clear();
-            }
+async fn opensearch(State(state): State<HttpState>, headers: HeaderMap) -> Response {
+    (
+        [(
+            header::CONTENT_TYPE,
+            "application/opensearchdescription+xml",
+        )],
+        opensearch_description(&base_url(&state.config, &headers)),
+    )
+        .into_response()
+}
+
+/** The nearest pages to a query or page, the most recently indexed first. */
+async fn feed(
+    State(state): State<HttpState>,
+    headers: HeaderMap,
+    Form(params): Params,
+) -> Response {
+    let param = |name: &str| param(&params, name);
+    let (title, search) = if let Some(q) = param("q") {
+        (q.to_string(), format!("q={}", urlencoding::encode(q)))
+    } else if let Some(s) = param("s") {
+        (
+            format!("pages like {}", s),
+            format!("s={}", urlencoding::encode(s)),
+        )
+    } else if let Some(t) = param("t") {
+        (
+            "pages like a topic".to_string(),
+            format!("t={}", urlencoding::encode(t)),
+        )
+    } else {
+        return (StatusCode::BAD_REQUEST, "Parameter q, s or t is needed").into_response();
+    };
+    let Some(result) = run_search(&state, &params, FEED_ENTRIES, 0).await else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Parameters s and t must look like instance:page",
+        )
+            .into_response();
+    };
+    match result {
+        Ok(mut result) => {
+            result.pages.sort_by_key(|p| Reverse(feed_time(p)));
+            (
+                [(header::CONTENT_TYPE, "application/atom+xml")],
+                atom_feed(
+                    &title,
+                    &base_url(&state.config, &headers),
+                    &search,
+                    &result.pages,
+                ),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            eprintln!("[HTTP] Feed failed: {}", e);
+            (status_of(&e), e.to_string()).into_response()
+        }
+    }
+}
+
+/**
+ * Where users reach this instance, links in documents for other programs have to be absolute.
+ * Without web_url we trust the Host header.
+ */
+fn base_url(config: &Config, headers: &HeaderMap) -> String {
+    if !config.web_url.is_empty() {
+        return config.web_url.trim_end_matches('/').to_string();
+    }
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or(&config.web_listen_address);
+    format!("http://{}", host)
+}
+
+/** OpenSearch suggestions: the query followed by a list of completions. */
+async fn suggest(State(state): State<HttpState>, Form(params): Params) -> Response {
+    let prefix = param(&params, "q").unwrap_or_default().to_string();
+    let suggestions = if prefix.trim().is_empty() {
+        Vec::new()
+    } else {
+        let result = request(&state.tx, |otx| Suggest {
+            prefix: prefix.clone(),
+            otx,
+        })
+        .await;
+        // Browsers just show no suggestions, there is no use in sending them an error.
+        result.unwrap_or_else(|e| {
+            eprintln!("[HTTP] Suggest failed: {}", e);
+            Vec::new()
+        })
+    };
+    (
+        [(header::CONTENT_TYPE, "application/x-suggestions+json")],
+        serde_json::json!([prefix, suggestions]).to_string(),
+    )
+        .into_response()
+}
+
+async fn not_found() -> impl IntoResponse {
+    (StatusCode::NOT_FOUND, "Not found")
+}
+
+async fn api(
+    State(state): State<HttpState>,
+    Path(endpoint): Path<String>,
+    Form(params): Params,
+) -> Response {
+    if state.config.debug > 0 {
+        println!("[HTTP] API request for {}", endpoint);
+    }
+    handle_api(&endpoint, &params, &state.tx)
+        .await
+        .into_response()
+}
+
+async fn index(State(state): State<HttpState>, Form(params): Params) -> Response {
+    let param = |name: &str| param(&params, name);
+    let limit = DEFAULT_RESULTS;
+    let page: usize = match param("page") {
+        Some(p) => p.parse().unwrap_or(0),
+        None => 1,
+    };
+    // Also keeps page * limit from overflowing.
+    let Some(offset) = page_offset(page, limit) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Parameter page must be a number from 1 to {}",
+                MAX_RESULTS / limit
+            ),
+        )
+            .into_response();
+    };
 
-            let mut query = String::new();
-            let results = match kv {
-                Some((key, value)) => {
-                    let start = Instant::now();
-                    let (otx, orx) = oneshot::channel();
-                    if key == "q" {
-                        query = urlencoding::decode(value)
-                            .expect("Url decode")
-                            .to_string()
-                            .replace("+", " ");
-                        tx.send(TextSearch {
-                            otx,
-                            query: query.clone(),
-                        })
-                        .unwrap();
-                    } else if key == "s" {
-                        let vv: Vec<&str> = value.split(":").collect();
-                        if vv.len() != 2 {
-                            return; // TODO: Send error back.
-                        }
-                        tx.send(MoreLikeSearch {
-                            otx,
-                            instance_id: vv[0].to_string(),
-                            page_id: str::parse(vv[1]).unwrap(),
-                        })
-                        .unwrap();
-                    }
-                    let result = orx.await.expect("Receiving results");
-                    if config.debug > 0 {
-                        println!("[HTTP] Got back {} results", result.pages.len());
-                    }
-                    Some(format_results(&result, start.elapsed()))
-                }
-                None => None,
-            };
-
-            socket
-                .write_all(
-                    "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\r\n".as_bytes(),
-                )
+    let (query, search) = if let Some(q) = param("q") {
+        (q.to_string(), format!("q={}", urlencoding::encode(q)))
+    } else if let Some(s) = param("s") {
+        (String::new(), format!("s={}", urlencoding::encode(s)))
+    } else if let Some(t) = param("t") {
+        (String::new(), format!("t={}", urlencoding::encode(t)))
+    } else {
+        return Html(main_page(&state.config)).into_response();
+    };
+    let by_topic = param("view") == Some("topics");
+    let (limit, offset) = if by_topic {
+        (TOPIC_CANDIDATES, 0)
+    } else {
+        (limit, offset)
+    };
+
+    let start = Instant::now();
+    let Some(result) = run_search(&state, &params, limit, offset).await else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Parameters s and t must look like instance:page",
+        )
+            .into_response();
+    };
+    let result = match result {
+        Ok(result) if by_topic => {
+            group_by_topic(&state, &query, result)
                 .await
-                .unwrap();
-            if let Some(r) = results {
-                if let Err(e) = socket.write_all(results_page(&query, &r).as_bytes()).await {
-                    eprintln!("[HTTP] Error writing output for results: {}", e);
-                }
-            } else {
-                if let Err(e) = socket.write_all(main_page().as_bytes()).await {
-                    eprintln!("[HTTP] Error writing output for main: {}", e);
-                }
+                .map(|(result, topics)| {
+                    format_topics(&result, &topics, start.elapsed(), &query, &search)
+                })
+        }
+        Ok(result) => {
+            if state.config.debug > 0 {
+                println!("[HTTP] Got back {} results", result.pages.len());
             }
-        });
+            let has_next = result.pages.len() == limit && page_offset(page + 1, limit).is_some();
+            Ok(topics_link(&search)
+                + &format_results(&result, start.elapsed(), &query)
+                + &pagination(&search, page, has_next)
+                + &feed_link(&search))
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(results) => Html(results_page(&query, &results)).into_response(),
+        Err(e) => {
+            eprintln!("[HTTP] Search failed: {}", e);
+            (
+                status_of(&e),
+                [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+                results_page(&query, &format_error(&e)),
+            )
+                .into_response()
+        }
     }
 }
+
+/** Group the pages of the result by topic, the result is returned without them. */
+async fn group_by_topic(
+    state: &HttpState,
+    query: &str,
+    mut result: SearchResult,
+) -> Result<(SearchResult, Vec<Topic>), SearchError> {
+    let pages = std::mem::take(&mut result.pages);
+    let topics = request(&state.tx, |otx| Topics {
+        pages,
+        query: query.to_string(),
+        otx,
+    })
+    .await?;
+    Ok((result, topics))
+}
+
+/**
+ * Search for q, for pages like s or for pages like the topic t. None when s or t is not a
+ * valid page reference, the caller checks that one of them is there.
+ */
+async fn run_search(
+    state: &HttpState,
+    params: &[(String, String)],
+    limit: usize,
+    offset: usize,
+) -> Option<Result<SearchResult, SearchError>> {
+    let param = |name: &str| param(&params, name);
+    let (otx, orx) = oneshot::channel();
+    let message = if let Some(q) = param("q") {
+        TextSearch {
+            otx,
+            query: q.to_string(),
+            limit,
+            offset,
+        }
+    } else if let Some(s) = param("s") {
+        let (instance_id, page_id) = parse_page_reference(s)?;
+        MoreLikeSearch {
+            otx,
+            instance_id,
+            page_id,
+            limit,
+            offset,
+        }
+    } else {
+        // A topic is a list of pages, separated by commas.
+        let pages = param("t")?
+            .split(',')
+            .take(TOPIC_REFERENCES)
+            .map(parse_page_reference)
+            .collect::<Option<Vec<(String, usize)>>>()?;
+        TopicSearch {
+            otx,
+            pages,
+            limit,
+            offset,
+        }
+    };
+    let result = match send(&state.tx, message).await {
+        Ok(()) => orx.await.map_err(SearchError::from).and_then(|r| r),
+        Err(e) => Err(e),
+    };
+    Some(result)
+}
+
+/** The first value of a query parameter. */
+pub fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/** Parse a reference to a page like 'instance:page', the instance is empty for our own pages. */
+pub fn parse_page_reference(s: &str) -> Option<(String, usize)> {
+    let (instance_id, page_id) = s.split_once(':')?;
+    Some((instance_id.to_string(), page_id.parse().ok()?))
+}
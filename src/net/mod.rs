@@ -17,6 +17,7 @@
    along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+mod api;
 pub mod http_service;
 pub mod udp_packets;
 pub mod udp_service;
@@ -19,6 +19,9 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::embedding::embedding_model::default_model_identity;
+use crate::search::query::SearchFilter;
+
 /**
  * With the IPv4 header being 20 bytes and the UDP header being 8 bytes, the payload of a UDP packet should be no larger than 1500 - 20 - 8 = 1472 bytes to avoid fragmentation.
  */
@@ -36,6 +39,21 @@ pub enum UdpPacket {
         #[serde(rename = "em")]
         #[serde(with = "serde_bytes")]
         embedding: Vec<u8>, // 1152
+        /** Maximum number of pages to send back. Older instances don't send this. */
+        #[serde(rename = "rc")]
+        #[serde(default)]
+        result_count: Option<usize>,
+        /** Only return pages matching this. Left out when there is nothing to filter on. */
+        #[serde(rename = "fi")]
+        #[serde(default)]
+        filter: Option<SearchFilter>,
+        /**
+         * The query without operators, the responder uses it for full text matching and to pick
+         * snippets. None for similar pages.
+         */
+        #[serde(rename = "qu")]
+        #[serde(default)]
+        query: Option<String>,
     },
     // /** Responder -> Searcher. The results we have available. */
     // SearchSummary { search_id: u64, distances: Vec<f32> },
@@ -53,12 +71,21 @@ pub enum UdpPacket {
         #[serde(rename = "ti")]
         title: String, // 200?
         #[serde(rename = "te")]
-        text: String, // 500?
+        /** The snippet, see snippet::SNIPPET_LEN. */
+        text: String,
 
         #[serde(rename = "ii")]
         instance_id: String,
         #[serde(rename = "pi")]
         page_id: usize,
+        /** When the page was fetched, in seconds since the epoch. */
+        #[serde(rename = "fe")]
+        #[serde(default)]
+        fetched: Option<u64>,
+        /** Three letter language code. */
+        #[serde(rename = "la")]
+        #[serde(default)]
+        language: Option<String>,
     },
     Insert {
         #[serde(rename = "us")]
@@ -70,6 +97,10 @@ pub enum UdpPacket {
         #[serde(rename = "xs")]
         #[serde(with = "serde_bytes")]
         text_smaz: Vec<u8>,
+        /** When the page was fetched, in seconds since the epoch. */
+        #[serde(rename = "fe")]
+        #[serde(default)]
+        fetched: Option<u64>,
     },
     GetEmbedding {
         #[serde(rename = "si")]
@@ -95,6 +126,10 @@ pub enum UdpPacket {
         accept_insert: bool,
         #[serde(rename = "pi")]
         pages_indexed: usize,
+        /** Model the embeddings are calculated with, see ModelSettings::identity. */
+        #[serde(rename = "mo")]
+        #[serde(default)]
+        model: Option<String>,
     },
     #[serde(rename = "p")]
     Peers {
@@ -115,4 +150,18 @@ pub struct PeerInfo {
     pub accept_insert: bool,
     #[serde(rename = "pi")]
     pub pages_indexed: usize,
+    /** None for instances that don't announce their model. */
+    #[serde(rename = "mo")]
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+impl PeerInfo {
+    /** Can we search this peer with embeddings of our model? */
+    pub fn compatible_with(&self, model_identity: &str) -> bool {
+        match &self.model {
+            Some(model) => model == model_identity,
+            None => model_identity == default_model_identity(),
+        }
+    }
 }
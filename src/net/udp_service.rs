@@ -18,12 +18,15 @@
 */
 
 use crate::config::Config;
+use crate::embedding::embedding_model::ModelSettings;
 use crate::net::udp_packets::{PeerInfo, UdpPacket};
-use crate::search::page_source::ExtractedPage;
-use crate::search::search_msg::SearchMsg;
+use crate::search::page_source::{ExtractedPage, PAGE_START_LEN};
+use crate::search::query::{ParsedQuery, SearchFilter};
+use crate::search::search_msg::{SearchError, SearchMsg, DEFAULT_RESULTS};
+use crate::search::snippet::SNIPPET_LEN;
 use crate::search::vector::ToFrom24;
 use crate::util::{now, slice_up_to};
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use rand::distributions::Alphanumeric;
 use rand::seq::SliceRandom;
 use rand::Rng;
@@ -31,7 +34,9 @@ use rmp_serde::{Deserializer, Serializer};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
 use tokio::sync::oneshot;
@@ -76,6 +81,8 @@ pub struct PageFromNetwork {
     pub url: String,
     pub title: String,
     pub text: String,
+    pub fetched: u64,
+    pub language: String,
 }
 
 pub struct ActiveSearch {
@@ -91,8 +98,9 @@ pub struct ActiveSearch {
 }
 
 pub struct ActiveGetEmbedding {
+    deadline: Instant,
     /** Channel to which we send the results. */
-    tx: oneshot::Sender<Vec<f32>>,
+    tx: oneshot::Sender<Result<Vec<f32>, UdpError>>,
 }
 
 #[derive(Debug)]
@@ -103,18 +111,60 @@ pub struct NetworkSearchResult {
     pub pages_searched: usize,
 }
 
+/** How long we wait for a peer to send us the embedding of one of its pages. */
+const GET_EMBEDDING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/** Longer queries are cut off, peers only use them for full text matching and snippets. */
+const MAX_QUERY_LEN: usize = 200;
+
+#[derive(Debug)]
+pub enum UdpError {
+    /** We don't know the instance, or it uses another model. */
+    UnknownInstance(String),
+    /** The peer did not answer in time. */
+    Timeout,
+    /** The peer sent something we could not decode. */
+    InvalidPacket(String),
+    /** The UDP service went away before it replied. */
+    Stopped,
+}
+
+impl fmt::Display for UdpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UdpError::UnknownInstance(id) => write!(f, "Unknown instance {}", id),
+            UdpError::Timeout => write!(f, "The instance did not answer in time"),
+            UdpError::InvalidPacket(e) => write!(f, "Invalid packet: {}", e),
+            UdpError::Stopped => write!(f, "The UDP service stopped"),
+        }
+    }
+}
+
+impl Error for UdpError {}
+
+impl From<oneshot::error::RecvError> for UdpError {
+    fn from(_: oneshot::error::RecvError) -> Self {
+        UdpError::Stopped
+    }
+}
+
 /** Messages sent to the UDP service. */
 #[derive(Debug)]
 pub enum UdpMsg {
+    /** Search our peers. Peers that don't answer in time are left out of the result. */
     Search {
         embedding: Vec<f32>,
         distance_limit: Option<f32>,
-        tx: oneshot::Sender<NetworkSearchResult>,
+        count: usize,
+        /** The query without operators, empty when searching for similar pages. */
+        query: String,
+        filter: SearchFilter,
+        tx: oneshot::Sender<Result<NetworkSearchResult, UdpError>>,
     },
     GetEmbedding {
         instance_id: String,
         page_id: usize,
-        tx: oneshot::Sender<Vec<f32>>,
+        tx: oneshot::Sender<Result<Vec<f32>, UdpError>>,
     },
     Tick {},
     Announce {},
@@ -125,13 +175,17 @@ pub enum UdpMsg {
 
 pub struct UdpService {
     pub search_tx: SyncSender<SearchMsg>,
-    pub udp_rx: tokio::sync::mpsc::Receiver<UdpMsg>,
+    /** Pages inserted by peers go here, see SearchService::index_rx. */
+    pub index_tx: SyncSender<SearchMsg>,
+    /** Shared, so a restarted service receives the messages sent to the previous one. */
+    pub udp_rx: Arc<tokio::sync::Mutex<tokio::sync::mpsc::Receiver<UdpMsg>>>,
     pub config: Config,
 }
 
 impl UdpService {
-    pub async fn start(mut self) {
-        self.run().await.unwrap();
+    /** Returns when all senders are gone, or with an error when the socket fails. */
+    pub async fn start(mut self) -> anyhow::Result<()> {
+        self.run().await.map_err(|e| anyhow!("{}", e))
     }
 
     async fn run(&mut self) -> Result<(), Box<dyn Error>> {
@@ -140,7 +194,8 @@ impl UdpService {
         let listening_port = socket.local_addr()?.port();
         println!("[UDP] Listening on {}", socket.local_addr()?);
 
-        let mut buf = [0u8; 2000];
+        // Embeddings of bigger models don't fit in a single 1500 byte packet.
+        let mut buf = vec![0u8; 65536];
         let mut send_buf = Vec::new();
 
         let mut known_peers: Vec<PeerInfo> = Vec::new();
@@ -153,11 +208,21 @@ impl UdpService {
             .map(char::from)
             .collect();
         println!("[UDP] My ID is {}", my_id);
+        let model_identity = ModelSettings::from_config(&self.config).identity();
+        let udp_rx = self.udp_rx.clone();
+        let mut udp_rx = udp_rx.lock().await;
 
         loop {
             tokio::select! {
                 v = socket.recv_from(&mut buf) => {
-                    let (len, addr) = v.unwrap();
+                    let (len, addr) = match v {
+                        Ok(v) => v,
+                        Err(e) => {
+                            // For example an ICMP port unreachable of a packet we sent earlier.
+                            eprintln!("[UDP] Error receiving packet {}", e);
+                            continue;
+                        }
+                    };
                     let mut de = Deserializer::new(&buf[..len]);
                     let message: UdpPacket = match Deserialize::deserialize(&mut de) {
                         Ok(m) => m,
@@ -171,24 +236,41 @@ impl UdpService {
                     }
 
                     match message {
-                        UdpPacket::Search { search_id, distance_limit, embedding } => {
+                        UdpPacket::Search { search_id, distance_limit, embedding, result_count, filter, query } => {
                             // Slightly hacky way to make sure we don't send searches to ourselves by accident.
                             // TODO: using the ID of a peer for this would be better.
                             if active_searches.contains_key(&search_id) {
                                 continue;
                             }
 
-                            let em = Vec::<f32>::from24(&embedding).unwrap();
+                            let em = match Vec::<f32>::from24(&embedding) {
+                                Ok(em) => em,
+                                Err(e) => {
+                                    eprintln!("[UDP] Search from {} with a bad embedding: {}", addr, e);
+                                    continue;
+                                }
+                            };
                             // Send search message to searchprovider.
                             let (otx, orx) = oneshot::channel();
                             self.search_tx
                                 .send(SearchMsg::EmbeddingSearch {
                                     otx,
-                                    embedding: em.to_vec(),
+                                    embedding: em,
+                                    query: ParsedQuery {
+                                        text: query.unwrap_or_default(),
+                                        filter: filter.unwrap_or_default(),
+                                    },
                                     search_remote: false,
-                                })
-                                .unwrap();
-                            let result = orx.await.expect("Receiving results");
+                                    limit: result_count.unwrap_or(DEFAULT_RESULTS),
+                                    offset: 0,
+                                })?;
+                            let result = match orx.await.map_err(SearchError::from).and_then(|r| r) {
+                                Ok(result) => result,
+                                Err(e) => {
+                                    eprintln!("[UDP] Search for {} failed: {}", addr, e);
+                                    continue;
+                                }
+                            };
                             if self.config.debug > 0 {
                                 println!("[UDP] Search: got back from search_provider {:?}", result);
                             }
@@ -206,17 +288,19 @@ impl UdpService {
                                     distance: page.distance,
                                     url: page.url,
                                     title: page.title,
-                                    text: slice_up_to(&page.text, 500).to_string(),
+                                    text: slice_up_to(&page.text, SNIPPET_LEN).to_string(),
+                                    fetched: Some(page.fetched),
+                                    language: Some(page.language),
                                 };
                                 send_buf.clear();
-                                m.serialize(&mut Serializer::new(&mut send_buf)).unwrap();
+                                m.serialize(&mut Serializer::new(&mut send_buf))?;
                                 socket.send_to(&send_buf, &addr).await?;
                             }
                         }
                         UdpPacket::Peers { peers } => {
                             known_peers = peers;
                         }
-                        UdpPacket::Page { search_id, distance, url, title, text, instance_id, page_id } => {
+                        UdpPacket::Page { search_id, distance, url, title, text, instance_id, page_id, fetched, language } => {
                             if let Some(q) = active_searches.get_mut(&search_id) {
                                 q.results.push(PageFromNetwork {
                                     instance_id,
@@ -224,29 +308,28 @@ impl UdpService {
                                     distance,
                                     url,
                                     title,
-                                    text });
+                                    text,
+                                    fetched: fetched.unwrap_or(0),
+                                    language: language.unwrap_or_default() });
                             } else {
                                 println!("Search result for unknown search {}", search_id);
                             }
                         },
-                        UdpPacket::Insert { url_smaz, title_smaz, text_smaz } => {
+                        UdpPacket::Insert { url_smaz, title_smaz, text_smaz, fetched } => {
                             if !self.config.accept_insert {
                                 continue;
                             }
-                            let url = String::from_utf8_lossy(&smaz::decompress(&url_smaz).unwrap()).to_string();
-                            let title = String::from_utf8_lossy(&smaz::decompress(&title_smaz).unwrap()).to_string();
-                            let text = String::from_utf8_lossy(&smaz::decompress(&text_smaz).unwrap()).to_string();
-                            let mut combined = title.to_string();
-                            combined.push(' ');
-                            combined.push_str(&text);
+                            let decompress = |data: &[u8]| smaz::decompress(data).map(|d| String::from_utf8_lossy(&d).to_string());
+                            let (url, title, text) = match (decompress(&url_smaz), decompress(&title_smaz), decompress(&text_smaz)) {
+                                (Ok(url), Ok(title), Ok(text)) => (url, title, text),
+                                _ => {
+                                    eprintln!("[UDP] Insert from {} could not be decompressed", addr);
+                                    continue;
+                                }
+                            };
                             println!("Received insert for {}", url);
-                            self.search_tx.send(SearchMsg::ExtractedPage {
-                                page: ExtractedPage {
-                                    url,
-                                    title,
-                                    text,
-                                    combined
-                                },
+                            self.index_tx.send(SearchMsg::ExtractedPage {
+                                page: ExtractedPage::new(url, title, text, fetched.unwrap_or_else(now), String::new()),
                                 from_network: true
                             })?;
                         }
@@ -261,31 +344,44 @@ impl UdpService {
                             self.search_tx.send(SearchMsg::GetEmbedding {
                                 page_id,
                                 otx,
-                            }).unwrap();
-                            let em: Vec<f32> = orx.await.unwrap();
+                            })?;
+                            // The peer gives up waiting when we don't answer.
+                            let em: Vec<f32> = match orx.await.map_err(SearchError::from).and_then(|r| r) {
+                                Ok(em) => em,
+                                Err(e) => {
+                                    eprintln!("[UDP] GetEmbedding for {} failed: {}", addr, e);
+                                    continue;
+                                }
+                            };
                             if self.config.debug > 0 {
                                 println!("[UDP] GetEmbedding: got a vector of length {} back from Search", em.len());
                             }
                             let m = UdpPacket::Embedding {
                                 search_id,
-                                embedding: em.to24().as_slice().try_into().unwrap(),
+                                embedding: em.to24(),
                             };
                             send_buf.clear();
-                            m.serialize(&mut Serializer::new(&mut send_buf)).unwrap();
+                            m.serialize(&mut Serializer::new(&mut send_buf))?;
                             socket.send_to(&send_buf, &addr).await?;
                         },
                         UdpPacket::Embedding { search_id, embedding } => {
                             if let Some(x) = active_get_embeddings.remove(&search_id) {
-                                x.tx.send(Vec::<f32>::from24(&embedding).unwrap().to_vec()).unwrap();
+                                let embedding = Vec::<f32>::from24(&embedding)
+                                    .map_err(|e| UdpError::InvalidPacket(e.to_string()));
+                                // The requester may have gone away.
+                                let _ = x.tx.send(embedding);
                             } else {
                                 eprintln!("[UDP] Got embedding, but could not find active search {}", search_id);
                             }
                         }
                     }
                 }
-                v = self.udp_rx.recv() => {
+                v = udp_rx.recv() => {
                     // Message to the UDP service
-                    let m = v.unwrap();
+                    let Some(m) = v else {
+                        // Nobody can send us anything anymore.
+                        return Ok(());
+                    };
                     if self.config.debug > 0 {
                         match m {
                             UdpMsg::Tick {} => {},
@@ -294,14 +390,16 @@ impl UdpService {
                     }
 
                     match m {
-                        UdpMsg::Search { embedding, distance_limit, tx } => {
+                        UdpMsg::Search { embedding, distance_limit, count, query, filter, tx } => {
                             let search_id: u64 = rand::thread_rng().gen();
                             println!("[UDP] Search started with id {}", search_id);
+                            // The embeddings of peers with another model mean something else.
+                            let search_peers: Vec<&PeerInfo> = known_peers.iter().filter(|p| p.compatible_with(&model_identity)).collect();
                             let mut deadline = Instant::now();
-                            if known_peers.len() > 0 {
-                                deadline = deadline.checked_add(Duration::from_millis(200)).unwrap();
+                            if search_peers.len() > 0 {
+                                deadline += Duration::from_millis(200);
                             }
-                            active_searches.insert(search_id, ActiveSearch {
+                            let mut active_search = ActiveSearch {
                                 search_id,
                                 results: Vec::new(),
                                 deadline,
@@ -309,35 +407,49 @@ impl UdpService {
                                 servers_contacted: 0,
                                 servers_responded: 0,
                                 pages_searched: 0,
-                            });
+                            };
 
                             // Let's fire this one off to our peers.
-                            for peer in &known_peers {
+                            let m = UdpPacket::Search {
+                                search_id,
+                                distance_limit,
+                                embedding: embedding.to24(),
+                                result_count: Some(count),
+                                filter: if filter.is_empty() { None } else { Some(filter.clone()) },
+                                query: if query.is_empty() { None } else { Some(slice_up_to(&query, MAX_QUERY_LEN).to_string()) },
+                            };
+                            send_buf.clear();
+                            m.serialize(&mut Serializer::new(&mut send_buf))?;
+                            for peer in search_peers {
                                 println!("[UDP] Sending search to peer {} at {}", peer.instance_id, peer.addr);
 
-                                active_searches.get_mut(&search_id).unwrap().servers_contacted += 1;
+                                active_search.servers_contacted += 1;
                                 // TODO: this is a bit optmistic, we should wait until we get a response from the peer.
-                                active_searches.get_mut(&search_id).unwrap().pages_searched += peer.pages_indexed;
+                                active_search.pages_searched += peer.pages_indexed;
 
-                                let m = UdpPacket::Search {
-                                    search_id,
-                                    distance_limit,
-                                    embedding: embedding.to24().as_slice().try_into().unwrap(),
-                                };
-                                send_buf.clear();
-                                m.serialize(&mut Serializer::new(&mut send_buf)).unwrap();
-                                socket.send_to(&send_buf, &peer.addr).await?;
+                                if let Err(e) = socket.send_to(&send_buf, &peer.addr).await {
+                                    eprintln!("[UDP] Failed to send search to {}: {}", peer.addr, e);
+                                }
                             }
+                            active_searches.insert(search_id, active_search);
                         }
                         UdpMsg::Tick { } => {
                             let searches_to_remove: Vec<u64> = active_searches.values().filter(|v| Instant::now() > v.deadline).map(|v| v.search_id).collect();
                             for t in searches_to_remove {
-                                let removed = active_searches.remove(&t).unwrap();
-                                removed.tx.send(NetworkSearchResult {
-                                    results: removed.results,
-                                    servers_contacted: removed.servers_contacted,
-                                    servers_responded: removed.servers_responded,
-                                    pages_searched: removed.pages_searched }).unwrap();
+                                if let Some(removed) = active_searches.remove(&t) {
+                                    // The requester may have gone away.
+                                    let _ = removed.tx.send(Ok(NetworkSearchResult {
+                                        results: removed.results,
+                                        servers_contacted: removed.servers_contacted,
+                                        servers_responded: removed.servers_responded,
+                                        pages_searched: removed.pages_searched }));
+                                }
+                            }
+                            let expired: Vec<u64> = active_get_embeddings.iter().filter(|(_, v)| Instant::now() > v.deadline).map(|(k, _)| *k).collect();
+                            for t in expired {
+                                if let Some(removed) = active_get_embeddings.remove(&t) {
+                                    let _ = removed.tx.send(Err(UdpError::Timeout));
+                                }
                             }
                             // Remove old peers.
                             known_peers.retain(|p| p.last_seen + 300 > now());
@@ -345,13 +457,21 @@ impl UdpService {
                         UdpMsg::Announce {} => {
                             #[cfg(feature = "upnp")]
                             if self.config.upnp_enabled {
-                                update_upnp(listening_port)?;
+                                if let Err(e) = update_upnp(listening_port) {
+                                    eprintln!("[UDP] UPnP failed: {}", e);
+                                }
                             }
 
                             // Query the search service for the number of indexed pages.
                             let (otx, orx) = oneshot::channel();
-                            self.search_tx.send(SearchMsg::Stats { otx }).unwrap();
-                            let stats = orx.await.unwrap();
+                            self.search_tx.send(SearchMsg::Stats { otx })?;
+                            let stats = match orx.await.map_err(SearchError::from).and_then(|r| r) {
+                                Ok(stats) => stats,
+                                Err(e) => {
+                                    eprintln!("[UDP] Not announcing, no stats: {}", e);
+                                    continue;
+                                }
+                            };
                             if self.config.debug > 0 {
                                 println!("[UDP] Announce: got back from search_provider {:?}", stats);
                             }
@@ -361,11 +481,11 @@ impl UdpService {
                                 instance_id: my_id.clone(),
                                 accept_insert: self.config.accept_insert,
                                 pages_indexed: stats.pages_indexed,
+                                model: Some(model_identity.clone()),
                             };
                             send_buf.clear();
                             announce_message
-                                .serialize(&mut Serializer::new(&mut send_buf))
-                                .unwrap();
+                                .serialize(&mut Serializer::new(&mut send_buf))?;
                             for tracker in &self.config.trackers {
                                 println!("[UDP] Sending Announce to {}", tracker);
                                 if let Err(e) = socket.send_to(&send_buf, tracker).await {
@@ -377,37 +497,45 @@ impl UdpService {
                             let message = UdpPacket::Insert {
                                 url_smaz: smaz::compress(page.url.as_bytes()),
                                 title_smaz: smaz::compress(page.title.as_bytes()),
-                                text_smaz: smaz::compress(page.text.as_bytes()),
+                                // Whole pages don't fit in a packet, peers only get the start.
+                                text_smaz: smaz::compress(slice_up_to(&page.text, PAGE_START_LEN).as_bytes()),
+                                fetched: Some(page.fetched),
                             };
                             send_buf.clear();
                             message
-                                .serialize(&mut Serializer::new(&mut send_buf))
-                                .unwrap();
+                                .serialize(&mut Serializer::new(&mut send_buf))?;
                             println!("Insert message size {}", send_buf.len());
 
                             // For now, insert with three random peers.
                             let peers_that_accept_insert = known_peers.iter().filter(|p| p.accept_insert).collect::<Vec<&PeerInfo>>();
                             let peers: Vec<&PeerInfo> = { peers_that_accept_insert.choose_multiple(&mut rand::thread_rng(), 3).map(|x| *x).collect() } ;
                             for peer in peers {
-                                socket.send_to(&send_buf, &peer.addr).await?;
+                                if let Err(e) = socket.send_to(&send_buf, &peer.addr).await {
+                                    eprintln!("[UDP] Failed to send insert to {}: {}", peer.addr, e);
+                                }
                             }
                         }
                         UdpMsg::GetEmbedding { instance_id, page_id, tx } => {
-                            if let Some(instance) = known_peers.iter().find(|x| x.instance_id == instance_id) {
+                            if let Some(instance) = known_peers.iter().find(|x| x.instance_id == instance_id && x.compatible_with(&model_identity)) {
                                 send_buf.clear();
 
                                 let search_id: u64 = rand::thread_rng().gen();
-                                active_get_embeddings.insert(search_id, ActiveGetEmbedding {
-                                    tx,
-                                });
                                 let get_embedding_message = UdpPacket::GetEmbedding {
                                     search_id,
                                     page_id,
                                 };
-                                get_embedding_message.serialize(&mut Serializer::new(&mut send_buf)).unwrap();
-                                socket.send_to(&send_buf, &instance.addr).await?;
+                                get_embedding_message.serialize(&mut Serializer::new(&mut send_buf))?;
+                                // Without an answer the request times out.
+                                if let Err(e) = socket.send_to(&send_buf, &instance.addr).await {
+                                    eprintln!("[UDP] Failed to send GetEmbedding to {}: {}", instance.addr, e);
+                                }
+                                active_get_embeddings.insert(search_id, ActiveGetEmbedding {
+                                    deadline: Instant::now() + GET_EMBEDDING_TIMEOUT,
+                                    tx,
+                                });
                             } else {
                                 eprintln!("[UDP] UdpM::GetEmbedding, instance not found {}", instance_id);
+                                let _ = tx.send(Err(UdpError::UnknownInstance(instance_id)));
                             }
                         }
                     }
@@ -419,7 +547,7 @@ impl UdpService {
 
 #[cfg(feature = "upnp")]
 fn update_upnp(listening_port: u16) -> anyhow::Result<()> {
-    let network_interfaces = NetworkInterface::show().unwrap();
+    let network_interfaces = NetworkInterface::show()?;
     for itf in network_interfaces.iter() {
         for addr in &itf.addr {
             match addr {
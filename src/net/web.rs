@@ -19,7 +19,16 @@
 
 use std::time::Duration;
 
-use crate::{search::search_provider::SearchResult, util::slice_up_to};
+use crate::{
+    config::Config,
+    search::{
+        search_msg::SearchError,
+        search_provider::{FoundPage, SearchResult},
+        snippet::{is_term, query_terms, word_indices, SNIPPET_LEN},
+        topics::Topic,
+    },
+    util::{format_timestamp, now, slice_up_to},
+};
 
 /**
  * Who needs a templating engine when you've got format!?
@@ -32,6 +41,7 @@ pub fn page(title: &str, body: &str) -> String {
 <head>
 <title>{title}</title>
 <meta name="viewport" content="width=device-width, initial-scale=1.0">
+<link rel="search" type="application/opensearchdescription+xml" title="DawnSearch" href="/opensearch.xml">
 <style>
         body {{
             font-family: 'Open Sans Light','sans-serif', sans-serif;
@@ -195,6 +205,38 @@ pub fn page(title: &str, body: &str) -> String {
             background-color: #8350ff;
         }}
 
+        .pagination {{
+            display: flex;
+            gap: 1em;
+            margin-top: 1em;
+        }}
+        .feed {{
+            margin-top: 1em;
+            font-size: small;
+        }}
+        .view {{
+            margin-bottom: 1em;
+            font-size: small;
+        }}
+        .topic {{
+            margin-bottom: 2em;
+        }}
+        .topic-label {{
+            font-size: large;
+            border-bottom: 1px solid #c1c1c1;
+        }}
+        .topic-count {{
+            color: #888;
+            font-weight: normal;
+        }}
+        .topic-more {{
+            font-size: small;
+            font-weight: normal;
+        }}
+        .search-error {{
+            color: #a00;
+        }}
+
         @media (max-width: 1060px) {{
             .search {{
                 width: 95%;
@@ -216,8 +258,9 @@ pub fn page(title: &str, body: &str) -> String {
     )
 }
 
-pub fn main_page() -> String {
+pub fn main_page(config: &Config) -> String {
     let s = search_box("");
+    let privacy = privacy_notice(config);
     page(
         "DawnSearch",
         &format!(
@@ -254,8 +297,7 @@ pub fn main_page() -> String {
 </ul>
 <h3>Privacy</h3>
 <p>
-This DawnSearch instance does not actively collect data on access, and does not store searches. However, some information may be temporarily stored in log files. Due to the way DawnSearch works, a processed form of your 
-seach query is sent to other instances. <b>Do not use DawnSearch to search for any sensitive information.</b>
+{privacy}
 </p>
 <h3>Does this work as well as Google, Bing, Brave Search etc?</h3>
 <p>Currently, no. DawnSearch has just 0.1% of the data of one of a big dataset loaded. And this is still only a part of the internet. Over the next coming months the index will expand, and we will have to discover
@@ -281,6 +323,31 @@ if they give other people access, they will also have to share their modificatio
     )
 }
 
+/** What this instance keeps of searches, which depends on its config. */
+fn privacy_notice(config: &Config) -> String {
+    let mut notice =
+        "This DawnSearch instance does not actively collect data on access".to_string();
+    let persisted = config.embedding_cache_persist && config.embedding_cache_size > 0;
+    if persisted {
+        notice += ". Recent searches are kept in a cache, which is saved to disk";
+    } else if config.suggest_min_searches > 0 {
+        notice += ". Recent searches are kept in memory";
+    } else {
+        notice += ", and does not store searches";
+    }
+    if config.suggest_min_searches > 0 {
+        notice += &format!(
+            ", and those that were done at least {} times are suggested to other visitors \
+            while they type",
+            config.suggest_min_searches
+        );
+    }
+    notice
+        + ". However, some information may be temporarily stored in log files. Due to the way \
+        DawnSearch works, a processed form of your seach query is sent to other instances. \
+        <b>Do not use DawnSearch to search for any sensitive information.</b>"
+}
+
 pub fn results_page(search_query: &str, results: &str) -> String {
     let s = search_box(search_query);
     let title = format!("{} - DawnSearch", search_query);
@@ -313,46 +380,282 @@ fn search_box(search_query: &str) -> String {
     )
 }
 
-pub fn format_results(result: &SearchResult, elapsed: Duration) -> String {
-    let mut r = String::new();
+/** 'query' is used to highlight its words in the snippets, it is empty when exploring. */
+pub fn format_results(result: &SearchResult, elapsed: Duration, query: &str) -> String {
+    let terms = query_terms(query);
+    let mut r = searched(result, elapsed);
+    for page in &result.pages {
+        r += &format_page(page, &terms);
+    }
+    r
+}
+
+/**
+ * The results grouped by topic. Each topic links to pages like it, and 'search' is the query
+ * string of the search, to go back to the list.
+ */
+pub fn format_topics(
+    result: &SearchResult,
+    topics: &[Topic],
+    elapsed: Duration,
+    query: &str,
+    search: &str,
+) -> String {
+    let terms = query_terms(query);
+    let mut r = searched(result, elapsed);
     r += &format!(
-        "<p>Searched {} pages on {} instances in {:.2} seconds</p>",
-        result.pages_searched,
-        result.servers_contacted + 1,
-        elapsed.as_secs_f32()
+        r#"<div class="view"><a href="?{}">Show as a list</a></div>"#,
+        html_escape::encode_double_quoted_attribute(search)
     );
-    for result in &result.pages {
-        let url_encoded_u = html_escape::encode_double_quoted_attribute(&result.url);
-        let url_encoded = html_escape::encode_text(&result.url);
-        let title_encoded = html_escape::encode_text(&result.title);
-        let s = slice_up_to(&result.text, 400);
-        let text_encoded = html_escape::encode_text(s);
-        let distance = if result.distance < 0.0 {
-            0.0
+    for topic in topics {
+        let more = if topic.references.is_empty() {
+            String::new()
         } else {
-            result.distance
-        }; // Prevent -0.0 from showing up.
-        let explore = format!(
-            r#"<a href="?s={}:{}" title="Find pages like this one" class="result-explore">explore</a>"#,
-            result.instance_id, result.page_id
-        );
-        let exploring = if result.distance < 0.001 {
-            "exploring"
-        } else {
-            ""
+            let references: Vec<String> = topic
+                .references
+                .iter()
+                .map(|(instance_id, page_id)| format!("{}:{}", instance_id, page_id))
+                .collect();
+            format!(
+                r#"<a href="?t={}" class="topic-more">more like this topic</a>"#,
+                html_escape::encode_double_quoted_attribute(&urlencoding::encode(
+                    &references.join(",")
+                ))
+            )
         };
         r += &format!(
             r#"
+<div class="topic">
+<h2 class="topic-label">{} <span class="topic-count">{}</span> {more}</h2>
+"#,
+            html_escape::encode_text(&topic.label),
+            topic.pages.len()
+        );
+        for page in &topic.pages {
+            r += &format_page(page, &terms);
+        }
+        r += "</div>\n";
+    }
+    r
+}
+
+fn searched(result: &SearchResult, elapsed: Duration) -> String {
+    format!(
+        "<p>Searched {} pages on {} instances in {:.2} seconds</p>",
+        result.pages_searched,
+        result.servers_contacted + 1,
+        elapsed.as_secs_f32()
+    )
+}
+
+fn format_page(result: &FoundPage, terms: &[String]) -> String {
+    let url_encoded_u = html_escape::encode_double_quoted_attribute(&result.url);
+    let url_encoded = html_escape::encode_text(&result.url);
+    let title_encoded = html_escape::encode_text(&result.title);
+    let text_encoded = highlight(slice_up_to(&result.text, SNIPPET_LEN), terms);
+    let distance = if result.distance < 0.0 {
+        0.0
+    } else {
+        result.distance
+    }; // Prevent -0.0 from showing up.
+    let explore = format!(
+        r#"<a href="?s={}:{}" title="Find pages like this one" class="result-explore">explore</a>"#,
+        result.instance_id, result.page_id
+    );
+    let fetched = if result.fetched > 0 {
+        format!(
+            r#"<span class="result-date">{}</span>"#,
+            &format_timestamp(result.fetched)[..10]
+        )
+    } else {
+        String::new()
+    };
+    let duplicates = match result.duplicates {
+        0 => String::new(),
+        1 => r#"<span class="result-duplicates">1 copy</span>"#.to_string(),
+        n => format!(r#"<span class="result-duplicates">{} copies</span>"#, n),
+    };
+    let exploring = if result.distance < 0.001 {
+        "exploring"
+    } else {
+        ""
+    };
+    format!(
+        r#"
 <div class="result {exploring}"><div class="currently-exploring">Exploring</div>
-<div class="result-top">{:.2} {explore} <i class="result-url">{}</i></div>
+<div class="result-top">{:.2} {explore} {fetched} {duplicates} <i class="result-url">{}</i></div>
 <div class="result-title"><a href="{}">{}</a></div>
 <div class="result-text">
     {}...
 </div>
 </div>
 "#,
-            distance, url_encoded, url_encoded_u, title_encoded, text_encoded,
+        distance, url_encoded, url_encoded_u, title_encoded, text_encoded,
+    )
+}
+
+/** Escape the text for HTML and put the words of the query in bold. */
+fn highlight(text: &str, terms: &[String]) -> String {
+    let mut r = String::new();
+    let mut done = 0;
+    for (i, word) in word_indices(text) {
+        if is_term(word, terms) {
+            r += &html_escape::encode_text(&text[done..i]);
+            r += "<b>";
+            r += &html_escape::encode_text(word);
+            r += "</b>";
+            done = i + word.len();
+        }
+    }
+    r += &html_escape::encode_text(&text[done..]);
+    r
+}
+
+/**
+ * OpenSearch description, so browsers can add us as a search engine. 'base_url' is where
+ * users reach this instance, like https://dawnsearch.org, the templates have to be absolute.
+ */
+pub fn opensearch_description(base_url: &str) -> String {
+    let base = html_escape::encode_double_quoted_attribute(base_url);
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/" xmlns:moz="http://www.mozilla.org/2006/browser/search/">
+<ShortName>DawnSearch</ShortName>
+<Description>Search the web by meaning</Description>
+<InputEncoding>UTF-8</InputEncoding>
+<Url type="text/html" method="get" template="{base}/?q={{searchTerms}}"/>
+<Url type="application/x-suggestions+json" method="get" template="{base}/suggest?q={{searchTerms}}"/>
+<Url type="application/opensearchdescription+xml" rel="self" template="{base}/opensearch.xml"/>
+<moz:SearchForm>{base}/</moz:SearchForm>
+</OpenSearchDescription>
+"#
+    )
+}
+
+/**
+ * Atom feed of the pages of a search, newest first. 'search' is the query string of the
+ * search, like in pagination.
+ */
+pub fn atom_feed(title: &str, base_url: &str, search: &str, pages: &[FoundPage]) -> String {
+    let self_url =
+        html_escape::encode_double_quoted_attribute(&format!("{}/feed?{}", base_url, search))
+            .to_string();
+    let html_url =
+        html_escape::encode_double_quoted_attribute(&format!("{}/?{}", base_url, search))
+            .to_string();
+    let updated = pages.iter().map(feed_time).max().unwrap_or_else(now);
+    let mut r = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+<title>DawnSearch: {}</title>
+<link rel="self" href="{self_url}"/>
+<link rel="alternate" type="text/html" href="{html_url}"/>
+<id>{self_url}</id>
+<updated>{}</updated>
+<author><name>DawnSearch</name></author>
+"#,
+        html_escape::encode_text(title),
+        format_timestamp(updated)
+    );
+    for page in pages {
+        r += &format!(
+            r#"<entry>
+<title>{}</title>
+<link href="{}"/>
+<id>{}</id>
+<updated>{}</updated>
+<summary>{}...</summary>
+</entry>
+"#,
+            html_escape::encode_text(&page.title),
+            html_escape::encode_double_quoted_attribute(&page.url),
+            html_escape::encode_text(&page.url),
+            format_timestamp(feed_time(page)),
+            html_escape::encode_text(slice_up_to(&page.text, 400)),
+        );
+    }
+    r += "</feed>\n";
+    r
+}
+
+/**
+ * When the page appeared for the feed: when we indexed it, or when it was fetched when we
+ * don't know that.
+ */
+pub fn feed_time(page: &FoundPage) -> u64 {
+    if page.inserted > 0 {
+        page.inserted
+    } else {
+        page.fetched
+    }
+}
+
+/** Link to the feed of the current search. */
+pub fn feed_link(search: &str) -> String {
+    format!(
+        r#"<div class="feed"><a href="/feed?{}" type="application/atom+xml">Follow new pages in a feed</a></div>"#,
+        html_escape::encode_double_quoted_attribute(search)
+    )
+}
+
+/** Link to the results of the current search grouped by topic. */
+pub fn topics_link(search: &str) -> String {
+    format!(
+        r#"<div class="view"><a href="?{}&amp;view=topics">Group by topic</a></div>"#,
+        html_escape::encode_double_quoted_attribute(search)
+    )
+}
+
+/** Shown instead of the results when the search failed. */
+pub fn format_error(error: &SearchError) -> String {
+    format!(
+        r#"<p class="search-error">The search failed: {}</p>"#,
+        html_escape::encode_text(&error.to_string())
+    )
+}
+
+/**
+ * Links to the previous and next page of results. 'search' is the query string
+ * of the current search without the page, for example 'q=cats'.
+ */
+pub fn pagination(search: &str, page: usize, has_next: bool) -> String {
+    let mut r = String::new();
+    r += r#"<div class="pagination">"#;
+    if page > 1 {
+        r += &format!(
+            r#"<a href="?{}&amp;page={}">&laquo; Previous</a>"#,
+            html_escape::encode_double_quoted_attribute(search),
+            page - 1
+        );
+    }
+    if has_next {
+        r += &format!(
+            r#"<a href="?{}&amp;page={}">Next &raquo;</a>"#,
+            html_escape::encode_double_quoted_attribute(search),
+            page + 1
         );
     }
+    r += "</div>";
     r
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlight_escapes_text_and_marks_terms() {
+        let terms = vec!["rust".to_string()];
+        assert_eq!(
+            highlight("a <b> Rust & rust", &terms),
+            "a &lt;b&gt; <b>Rust</b> &amp; <b>rust</b>"
+        );
+        // Terms at the start and end, after multibyte text.
+        assert_eq!(
+            highlight("rust é rust", &terms),
+            "<b>rust</b> é <b>rust</b>"
+        );
+        assert_eq!(highlight("x < y", &[]), "x &lt; y");
+        assert_eq!(highlight("", &terms), "");
+    }
+}
@@ -0,0 +1,269 @@
+/*
+   Copyright 2023 Krol Inventions B.V.
+
+   This file is part of DawnSearch.
+
+   DawnSearch is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   DawnSearch is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::config::Config;
+use crate::embedding::embedding_model::ModelSettings;
+use crate::search::vector::{bytes_to_embedding, embedding_to_bytes};
+
+/** What an embedding in the cache was calculated from. */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CacheKey {
+    /** The text of a query, without operators. */
+    Query(String),
+    /** A page of a peer, we got the embedding over UDP. */
+    RemotePage { instance_id: String, page_id: usize },
+}
+
+impl CacheKey {
+    /**
+     * Queries that only differ in whitespace get the same embedding. Case is kept,
+     * not every model is uncased.
+     */
+    pub fn query(text: &str) -> CacheKey {
+        CacheKey::Query(text.split_whitespace().collect::<Vec<_>>().join(" "))
+    }
+
+    pub fn remote_page(instance_id: &str, page_id: usize) -> CacheKey {
+        CacheKey::RemotePage {
+            instance_id: instance_id.to_string(),
+            page_id,
+        }
+    }
+
+    /** How the key is stored in the database. */
+    fn to_db(&self) -> String {
+        match self {
+            CacheKey::Query(text) => format!("q:{}", text),
+            CacheKey::RemotePage {
+                instance_id,
+                page_id,
+            } => format!("p:{}:{}", instance_id, page_id),
+        }
+    }
+
+    fn from_db(key: &str) -> Option<CacheKey> {
+        if let Some(text) = key.strip_prefix("q:") {
+            return Some(CacheKey::Query(text.to_string()));
+        }
+        let (instance_id, page_id) = key.strip_prefix("p:")?.rsplit_once(':')?;
+        Some(CacheKey::remote_page(instance_id, page_id.parse().ok()?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub entries: usize,
+}
+
+struct Entry {
+    embedding: Vec<f32>,
+    /** When the entry was last used, its key in by_use. */
+    used: u64,
+    /** How often the entry was asked for since it was cached. */
+    uses: u32,
+}
+
+/**
+ * Least recently used cache of embeddings, so popular queries don't have to go through the
+ * model and pages of peers don't have to be fetched again. With embedding_cache_persist
+ * the cache is saved to and loaded from embedding_cache.sqlite in the data directory. The
+ * keys of queries are their text, so that file tells what was searched for recently.
+ */
+pub struct EmbeddingCache {
+    capacity: usize,
+    entries: HashMap<CacheKey, Entry>,
+    /** The keys by when they were last used, oldest first. */
+    by_use: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    hits: usize,
+    misses: usize,
+    sqlite: Option<rusqlite::Connection>,
+    model_identity: String,
+}
+
+impl EmbeddingCache {
+    pub fn new(config: &Config) -> Result<EmbeddingCache, anyhow::Error> {
+        let sqlite = if config.embedding_cache_persist && config.embedding_cache_size > 0 {
+            let sqlite = rusqlite::Connection::open(
+                Path::new(&config.data_dir).join("embedding_cache.sqlite"),
+            )?;
+            sqlite.execute(
+                "CREATE TABLE IF NOT EXISTS embedding_cache (
+                    key TEXT PRIMARY KEY,
+                    model TEXT NOT NULL,
+                    embedding BLOB NOT NULL,
+                    used INTEGER NOT NULL
+                )",
+                (),
+            )?;
+            Some(sqlite)
+        } else {
+            None
+        };
+        let mut cache = EmbeddingCache {
+            capacity: config.embedding_cache_size,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+            sqlite,
+            model_identity: ModelSettings::from_config(config).identity(),
+        };
+        cache.load()?;
+        Ok(cache)
+    }
+
+    /** Load the most recently used embeddings of our model. */
+    fn load(&mut self) -> Result<(), anyhow::Error> {
+        let Some(sqlite) = &self.sqlite else {
+            return Ok(());
+        };
+        let mut s = sqlite.prepare(
+            "SELECT key, embedding FROM embedding_cache WHERE model = ?1 ORDER BY used DESC LIMIT ?2",
+        )?;
+        let rows: Vec<(String, Vec<u8>)> = s
+            .query_map((&self.model_identity, self.capacity), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        drop(s);
+        // Oldest first, so they are also the first to be evicted again.
+        for (key, embedding) in rows.into_iter().rev() {
+            if let (Some(key), Ok(embedding)) =
+                (CacheKey::from_db(&key), bytes_to_embedding(&embedding))
+            {
+                self.put(key, embedding);
+            }
+        }
+        if !self.entries.is_empty() {
+            println!("[Search] Loaded {} cached embeddings", self.entries.len());
+        }
+        Ok(())
+    }
+
+    /** Save all cached embeddings, if the cache is persisted. */
+    pub fn save(&mut self) -> Result<(), anyhow::Error> {
+        let Some(sqlite) = &mut self.sqlite else {
+            return Ok(());
+        };
+        let tx = sqlite.transaction()?;
+        tx.execute(
+            "DELETE FROM embedding_cache WHERE model = ?1",
+            (&self.model_identity,),
+        )?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT OR REPLACE INTO embedding_cache (key, model, embedding, used) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (used, key) in &self.by_use {
+                insert.execute((
+                    key.to_db(),
+                    &self.model_identity,
+                    embedding_to_bytes(&self.entries[key].embedding)?,
+                    used,
+                ))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /** The cached embedding, counts as a hit or a miss. */
+    pub fn get(&mut self, key: &CacheKey) -> Option<Vec<f32>> {
+        let Some(entry) = self.entries.get_mut(key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        self.clock += 1;
+        self.by_use.remove(&entry.used);
+        entry.used = self.clock;
+        entry.uses += 1;
+        self.by_use.insert(self.clock, key.clone());
+        Some(entry.embedding.clone())
+    }
+
+    pub fn put(&mut self, key: CacheKey, embedding: Vec<f32>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        let entry = Entry {
+            embedding,
+            used: self.clock,
+            uses: 0,
+        };
+        if let Some(old) = self.entries.insert(key.clone(), entry) {
+            self.by_use.remove(&old.used);
+        }
+        self.by_use.insert(self.clock, key);
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    /**
+     * The most asked for queries in the cache that start with 'prefix', ignoring case. Only
+     * queries that were searched at least 'min_searches' times, none when it is 0.
+     */
+    pub fn popular_queries(&self, prefix: &str, count: usize, min_searches: usize) -> Vec<String> {
+        if min_searches == 0 {
+            return Vec::new();
+        }
+        let prefix = prefix.trim_start().to_lowercase();
+        let mut queries: Vec<(&String, &Entry)> = self
+            .entries
+            .iter()
+            .filter_map(|(key, entry)| match key {
+                // The first search puts it in the cache, every next one is a use.
+                CacheKey::Query(text)
+                    if entry.uses as usize + 1 >= min_searches
+                        && text.to_lowercase().starts_with(&prefix) =>
+                {
+                    Some((text, entry))
+                }
+                _ => None,
+            })
+            .collect();
+        queries.sort_by(|a, b| b.1.uses.cmp(&a.1.uses).then(b.1.used.cmp(&a.1.used)));
+        queries
+            .into_iter()
+            .take(count)
+            .map(|(text, _)| text.clone())
+            .collect()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+        }
+    }
+}
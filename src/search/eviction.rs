@@ -0,0 +1,161 @@
+/*
+   Copyright 2023 Krol Inventions B.V.
+
+   This file is part of DawnSearch.
+
+   DawnSearch is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   DawnSearch is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::bail;
+use rusqlite::Connection;
+
+use crate::search::best_results::{BestResults, NodeReference};
+use crate::search::vector::{Distance, EmbeddingStorage};
+use crate::util::now;
+
+/**
+ * Decides which pages to remove when the local page store is full.
+ */
+pub trait EvictionPolicy {
+    /** Create any tables the policy needs. */
+    fn init(&self, _sqlite: &Connection) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /** Called with the pages we returned for a search. */
+    fn returned(&self, _sqlite: &Connection, _ids: &[usize]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /** Select up to 'count' pages to remove. */
+    fn select(&self, sqlite: &Connection, count: usize) -> anyhow::Result<Vec<usize>>;
+}
+
+/**
+ * Create the policy configured by name. Returns None for "none", in which case
+ * we stop accepting pages when we are full.
+ */
+pub fn eviction_policy(
+    name: &str,
+    dimension: usize,
+) -> anyhow::Result<Option<Box<dyn EvictionPolicy>>> {
+    Ok(match name {
+        "none" => None,
+        "oldest" => Some(Box::new(OldestFirst {})),
+        "least_returned" => Some(Box::new(LeastRecentlyReturned {})),
+        "farthest" => Some(Box::new(FarthestFromRegion { dimension })),
+        _ => bail!("Unknown eviction policy: {}", name),
+    })
+}
+
+/** Remove the pages that were added first. Page ids are never reused, so that's the lowest ids. */
+pub struct OldestFirst {}
+
+impl EvictionPolicy for OldestFirst {
+    fn select(&self, sqlite: &Connection, count: usize) -> anyhow::Result<Vec<usize>> {
+        let mut s = sqlite.prepare("SELECT id FROM page ORDER BY id LIMIT ?1")?;
+        let ids = s
+            .query_map((count,), |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+}
+
+/** Remove the pages that have not been in search results for the longest time. */
+pub struct LeastRecentlyReturned {}
+
+impl EvictionPolicy for LeastRecentlyReturned {
+    fn init(&self, sqlite: &Connection) -> anyhow::Result<()> {
+        sqlite.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS page_returned (
+                id INTEGER PRIMARY KEY,
+                last_returned INTEGER NOT NULL
+            );
+            CREATE TRIGGER IF NOT EXISTS page_returned_delete AFTER DELETE ON page BEGIN
+                DELETE FROM page_returned WHERE id = old.id;
+            END;
+        ",
+        )?;
+        Ok(())
+    }
+
+    fn returned(&self, sqlite: &Connection, ids: &[usize]) -> anyhow::Result<()> {
+        let tx = sqlite.unchecked_transaction()?;
+        {
+            let mut s = tx.prepare(
+                "INSERT OR REPLACE INTO page_returned (id, last_returned) VALUES (?1, ?2)",
+            )?;
+            let now = now();
+            for id in ids {
+                s.execute((id, now))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn select(&self, sqlite: &Connection, count: usize) -> anyhow::Result<Vec<usize>> {
+        // Pages that were never returned go first, oldest first.
+        let mut s = sqlite.prepare(
+            "SELECT page.id FROM page LEFT JOIN page_returned ON page.id = page_returned.id
+            ORDER BY coalesce(page_returned.last_returned, 0), page.id LIMIT ?1",
+        )?;
+        let ids = s
+            .query_map((count,), |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+}
+
+/**
+ * Remove the pages that are farthest from the centroid of all our pages, so the node
+ * keeps the part of the semantic space it knows most about.
+ */
+pub struct FarthestFromRegion {
+    /** Length of the embeddings. */
+    pub dimension: usize,
+}
+
+impl EvictionPolicy for FarthestFromRegion {
+    fn select(&self, sqlite: &Connection, count: usize) -> anyhow::Result<Vec<usize>> {
+        let mut s = sqlite.prepare("SELECT id, embedding FROM page")?;
+
+        // First pass: where are we?
+        let mut centroid = vec![0.0f32; self.dimension];
+        let mut qq = s.query(())?;
+        while let Some(r) = qq.next()? {
+            let embedding: Vec<u8> = r.get(1)?;
+            let embedding = EmbeddingStorage::decode(&embedding, self.dimension)?;
+            for (c, e) in centroid.iter_mut().zip(embedding) {
+                *c += e;
+            }
+        }
+
+        // Second pass: find the pages with the lowest similarity to the centroid.
+        // The centroid doesn't have to be normalized for that.
+        let mut farthest = BestResults::new(count);
+        let mut qq = s.query(())?;
+        while let Some(r) = qq.next()? {
+            let id: usize = r.get(0)?;
+            let embedding: Vec<u8> = r.get(1)?;
+            let embedding = EmbeddingStorage::decode(&embedding, self.dimension)?;
+            farthest.insert(NodeReference {
+                id,
+                distance: embedding.distance_ip(&centroid),
+            });
+        }
+        Ok(farthest.results().iter().map(|r| r.id).collect())
+    }
+}
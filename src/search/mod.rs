@@ -18,8 +18,13 @@
 */
 
 pub mod best_results;
+pub mod embedding_cache;
+pub mod eviction;
 pub mod page_source;
+pub mod query;
 pub mod search_msg;
 pub mod search_provider;
 pub mod search_service;
+pub mod snippet;
+pub mod topics;
 pub mod vector;
@@ -32,12 +32,19 @@ use whichlang::Lang;
 
 use crate::index::extract::extract;
 use crate::index::extract::extract_text;
-use crate::util::slice_up_to;
+use crate::util::{content_hash, host_of, now, parse_timestamp, slice_up_to};
+
+/** The start of the text is embedded together with the title, as passage 0 of the page. */
+pub const PAGE_START_LEN: usize = 2048;
+/** The text after the start is split into passages of up to this many bytes. */
+const PASSAGE_LEN: usize = 1024;
 
 struct RecordOwned {
     uri: String,
     warc_type: String,
     payload_type: String,
+    record_id: String,
+    date: String,
     body: String,
 }
 
@@ -46,19 +53,118 @@ pub struct ExtractedPage {
     pub url: String,
     pub title: String,
     pub text: String,
+    /** Title and the start of the text. */
     pub combined: String,
+    /** When the page was fetched, in seconds since the epoch. */
+    pub fetched: u64,
+    /** Three letter language code, for example 'eng'. */
+    pub language: String,
+    pub host: String,
+    /** Empty if the page did not come from a WARC file. */
+    pub warc_record_id: String,
+    /** Hash of the text, to find copies of the same page. */
+    pub content_hash: String,
+}
+
+impl ExtractedPage {
+    pub fn new(
+        url: String,
+        title: String,
+        text: String,
+        fetched: u64,
+        warc_record_id: String,
+    ) -> ExtractedPage {
+        let mut combined = title.to_string();
+        combined.push(' ');
+        combined.push_str(slice_up_to(&text, PAGE_START_LEN));
+        let language = detect_language(&combined).three_letter_code().to_string();
+        let host = host_of(&url);
+        let content_hash = content_hash(&text);
+        ExtractedPage {
+            url,
+            title,
+            text,
+            combined,
+            fetched,
+            language,
+            host,
+            warc_record_id,
+            content_hash,
+        }
+    }
+
+    /**
+     * The parts of the text after PAGE_START_LEN, split at word boundaries, so content deep
+     * in a long page can be found too. Passage 0, the title and start of the text, is not
+     * included.
+     */
+    pub fn passages(&self) -> Vec<Passage> {
+        let mut passages = Vec::new();
+        let mut offset = slice_up_to(&self.text, PAGE_START_LEN).len();
+        while offset < self.text.len() {
+            let rest = &self.text[offset..];
+            let skipped = rest.len() - rest.trim_start().len();
+            offset += skipped;
+            let rest = &rest[skipped..];
+            if rest.is_empty() {
+                break;
+            }
+            let mut piece = slice_up_to(rest, PASSAGE_LEN);
+            if piece.len() < rest.len() {
+                // Don't cut words in half, unless there is no space at all.
+                if let Some(space) = piece.rfind(char::is_whitespace) {
+                    if space > PASSAGE_LEN / 2 {
+                        piece = &piece[..space];
+                    }
+                }
+            }
+            passages.push(Passage {
+                offset,
+                len: piece.len(),
+            });
+            offset += piece.len();
+        }
+        passages
+    }
+
+    /** The texts to embed: passage 0 (title and start of the text) and the other passages. */
+    pub fn embedding_texts(&self) -> Vec<String> {
+        let mut texts = vec![self.combined.clone()];
+        for passage in self.passages() {
+            texts.push(format!("{} {}", self.title, passage.text(&self.text)));
+        }
+        texts
+    }
+}
+
+/** A part of the text of a page, in bytes. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Passage {
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl Passage {
+    pub fn text<'a>(&self, text: &'a str) -> &'a str {
+        &text[self.offset..self.offset + self.len]
+    }
 }
 
 pub struct PageSource<T: Read> {
     reader: io::BufReader<MultiGzDecoder<T>>,
+    /** We keep at most this much of the text of a page, see Config::max_text_len. */
+    max_text_len: usize,
 }
 
 impl<T: Read> PageSource<T> {
-    pub fn read_warc_gz(input: T) -> PageSource<T> {
+    pub fn read_warc_gz(input: T, max_text_len: usize) -> PageSource<T> {
         const PER_THREAD_BUF_SIZE: usize = 16 * 1024 * 1024;
 
         let reader = io::BufReader::with_capacity(PER_THREAD_BUF_SIZE, MultiGzDecoder::new(input));
-        PageSource { reader }
+        PageSource {
+            reader,
+            max_text_len,
+        }
     }
     pub fn next(&mut self) -> Result<Option<ExtractedPage>, io::Error> {
         while let Some(record) = read_record(&mut self.reader)? {
@@ -110,22 +216,19 @@ impl<T: Read> PageSource<T> {
             }
 
             let title = slice_up_to(&title, 200);
-            let clean = slice_up_to(&clean, 2048);
-
-            let mut combined = title.to_string();
-            combined.push(' ');
-            combined.push_str(&clean);
+            let clean = slice_up_to(&clean, self.max_text_len);
 
-            let lang = detect_language(&combined);
-            if lang != Lang::Eng {
+            let page = ExtractedPage::new(
+                url.to_string(),
+                title.to_string(),
+                clean.to_string(),
+                parse_timestamp(&record.date).unwrap_or_else(now),
+                record.record_id,
+            );
+            if page.language != Lang::Eng.three_letter_code() {
                 continue;
             }
-            return Ok(Some(ExtractedPage {
-                url: url.to_string(),
-                title: title.to_string(),
-                text: clean.to_string(),
-                combined,
-            }));
+            return Ok(Some(page));
         }
         Ok(None)
     }
@@ -136,6 +239,8 @@ fn read_record(reader: &mut dyn io::BufRead) -> Result<Option<RecordOwned>, std:
     let mut uri = String::new();
     let mut warc_type = String::new();
     let mut payload_type = String::new();
+    let mut record_id = String::new();
+    let mut date = String::new();
 
     // Read headers
     for line in reader.lines() {
@@ -148,6 +253,8 @@ fn read_record(reader: &mut dyn io::BufRead) -> Result<Option<RecordOwned>, std:
                     uri: uri,
                     warc_type: warc_type,
                     payload_type,
+                    record_id,
+                    date,
                     body: String::new(),
                 }));
             }
@@ -165,6 +272,8 @@ fn read_record(reader: &mut dyn io::BufRead) -> Result<Option<RecordOwned>, std:
                     uri: uri,
                     warc_type: warc_type,
                     payload_type,
+                    record_id,
+                    date,
                     body: body.to_owned(),
                 }));
             }
@@ -172,6 +281,8 @@ fn read_record(reader: &mut dyn io::BufRead) -> Result<Option<RecordOwned>, std:
                 uri: uri,
                 warc_type: warc_type,
                 payload_type,
+                record_id,
+                date,
                 body: String::new(),
             }));
         }
@@ -190,6 +301,12 @@ fn read_record(reader: &mut dyn io::BufRead) -> Result<Option<RecordOwned>, std:
             if key == "WARC-Identified-Payload-Type" {
                 payload_type = value.to_owned();
             }
+            if key == "WARC-Record-ID" {
+                record_id = value.to_owned();
+            }
+            if key == "WARC-Date" {
+                date = value.to_owned();
+            }
         }
     }
     Ok(None)
@@ -0,0 +1,143 @@
+/*
+   Copyright 2023 Krol Inventions B.V.
+
+   This file is part of DawnSearch.
+
+   DawnSearch is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   DawnSearch is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::search::search_provider::FoundPage;
+use crate::util::parse_timestamp;
+
+/**
+ * Restrictions on which pages may be returned. Also sent to other instances
+ * as part of a search, so the field names are kept short.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct SearchFilter {
+    /** Only pages from one of these hosts (or their subdomains). */
+    #[serde(rename = "si")]
+    pub sites: Vec<String>,
+    /** No pages from these hosts (or their subdomains). */
+    #[serde(rename = "xs")]
+    pub excluded_sites: Vec<String>,
+    /** Three letter language code. */
+    #[serde(rename = "la")]
+    pub language: Option<String>,
+    /** Only pages fetched after this time, in seconds since the epoch. */
+    #[serde(rename = "af")]
+    pub after: Option<u64>,
+}
+
+impl SearchFilter {
+    pub fn is_empty(&self) -> bool {
+        self == &SearchFilter::default()
+    }
+
+    pub fn matches(&self, page: &FoundPage) -> bool {
+        if !self.sites.is_empty() && !self.sites.iter().any(|s| host_matches(&page.host, s)) {
+            return false;
+        }
+        if self
+            .excluded_sites
+            .iter()
+            .any(|s| host_matches(&page.host, s))
+        {
+            return false;
+        }
+        if let Some(language) = &self.language {
+            if &page.language != language {
+                return false;
+            }
+        }
+        if let Some(after) = self.after {
+            if page.fetched <= after {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn host_matches(host: &str, site: &str) -> bool {
+    host == site || host.ends_with(&format!(".{}", site))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParsedQuery {
+    /** The query without the operators, this is what we search for. */
+    pub text: String,
+    pub filter: SearchFilter,
+}
+
+/**
+ * Split the operators site:, -site:, lang: and after: from a query.
+ * Operators with a value we don't understand are left in the text.
+ */
+pub fn parse_query(query: &str) -> ParsedQuery {
+    let mut words = Vec::new();
+    let mut filter = SearchFilter::default();
+    for word in query.split_whitespace() {
+        let Some((operator, value)) = word.split_once(':') else {
+            words.push(word);
+            continue;
+        };
+        let value = value.to_lowercase();
+        match operator.to_lowercase().as_str() {
+            "site" if !value.is_empty() => filter.sites.push(value),
+            "-site" if !value.is_empty() => filter.excluded_sites.push(value),
+            "lang" => match language_code(&value) {
+                Some(code) => filter.language = Some(code.to_string()),
+                None => words.push(word),
+            },
+            "after" => match parse_timestamp(&value.to_uppercase()) {
+                Some(after) => filter.after = Some(after),
+                None => words.push(word),
+            },
+            _ => words.push(word),
+        }
+    }
+    ParsedQuery {
+        text: words.join(" "),
+        filter,
+    }
+}
+
+/** The three letter code that whichlang uses, from a two or three letter code. */
+fn language_code(lang: &str) -> Option<&'static str> {
+    const CODES: [(&str, &str); 16] = [
+        ("ar", "ara"),
+        ("zh", "cmn"),
+        ("de", "deu"),
+        ("en", "eng"),
+        ("fr", "fra"),
+        ("hi", "hin"),
+        ("it", "ita"),
+        ("ja", "jpn"),
+        ("ko", "kor"),
+        ("nl", "nld"),
+        ("pt", "por"),
+        ("ru", "rus"),
+        ("es", "spa"),
+        ("sv", "swe"),
+        ("tr", "tur"),
+        ("vi", "vie"),
+    ];
+    CODES
+        .iter()
+        .find(|(short, long)| *short == lang || *long == lang)
+        .map(|(_, long)| *long)
+}
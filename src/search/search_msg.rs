@@ -17,35 +17,182 @@
    along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use std::fmt;
+use std::sync::mpsc::SendError;
+
+use tokio::sync::oneshot::{self, error::RecvError};
+
 use super::page_source::ExtractedPage;
-use super::search_provider::{SearchResult, SearchStats};
+use super::query::ParsedQuery;
+use super::search_provider::{FoundPage, SearchResult, SearchStats};
+use super::topics::Topic;
+use crate::embedding::embedding_service::EmbeddingError;
+use crate::net::udp_service::UdpError;
+
+/** Number of results returned when nothing else was asked for. */
+pub const DEFAULT_RESULTS: usize = 20;
+/** We never search for more than this many results, also not when a peer asks us to. */
+pub const MAX_RESULTS: usize = 100;
+
+/**
+ * The offset of the first result on 'page', counting from 1. None for page 0 and for pages
+ * that start at or beyond MAX_RESULTS, so they can be refused before anything overflows.
+ */
+pub fn page_offset(page: usize, limit: usize) -> Option<usize> {
+    let offset = page.checked_sub(1)?.checked_mul(limit)?;
+    (offset < MAX_RESULTS).then_some(offset)
+}
+
+#[derive(Debug)]
+pub enum SearchError {
+    PageNotFound(usize),
+    /** Something the search service can't do anything with, like a malformed page reference. */
+    InvalidRequest(String),
+    Embedding(EmbeddingError),
+    Network(UdpError),
+    /** The database or the index failed. */
+    Storage(String),
+    /** The search service went away before it replied. */
+    Stopped,
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchError::PageNotFound(id) => write!(f, "Page {} not found", id),
+            SearchError::InvalidRequest(e) => write!(f, "Invalid request: {}", e),
+            SearchError::Embedding(e) => e.fmt(f),
+            SearchError::Network(e) => e.fmt(f),
+            SearchError::Storage(e) => write!(f, "Storage error: {}", e),
+            SearchError::Stopped => write!(f, "The search service stopped"),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+impl From<EmbeddingError> for SearchError {
+    fn from(e: EmbeddingError) -> Self {
+        SearchError::Embedding(e)
+    }
+}
+
+impl From<UdpError> for SearchError {
+    fn from(e: UdpError) -> Self {
+        SearchError::Network(e)
+    }
+}
+
+impl From<anyhow::Error> for SearchError {
+    fn from(e: anyhow::Error) -> Self {
+        SearchError::Storage(format!("{:#}", e))
+    }
+}
+
+impl From<RecvError> for SearchError {
+    fn from(_: RecvError) -> Self {
+        SearchError::Stopped
+    }
+}
+
+impl<T> From<SendError<T>> for SearchError {
+    fn from(_: SendError<T>) -> Self {
+        SearchError::Stopped
+    }
+}
+
+/** Where the search service sends its answer. */
+pub type Reply<T> = oneshot::Sender<Result<T, SearchError>>;
 
+/** Replies go to a oneshot channel, the requester may have gone away in the meantime. */
 #[derive(Debug)]
 pub enum SearchMsg {
     TextSearch {
-        otx: tokio::sync::oneshot::Sender<SearchResult>,
+        otx: Reply<SearchResult>,
+        /** May contain operators like site:, see query::parse_query. */
         query: String,
+        limit: usize,
+        offset: usize,
     },
     MoreLikeSearch {
-        otx: tokio::sync::oneshot::Sender<SearchResult>,
+        otx: Reply<SearchResult>,
         instance_id: String,
         page_id: usize,
+        limit: usize,
+        offset: usize,
+    },
+    /** Pages like a topic, by searching for the centre of the embeddings of its pages. */
+    TopicSearch {
+        otx: Reply<SearchResult>,
+        /** Instance and page id of the pages, see Topic::references. */
+        pages: Vec<(String, usize)>,
+        limit: usize,
+        offset: usize,
     },
     EmbeddingSearch {
-        otx: tokio::sync::oneshot::Sender<SearchResult>,
+        otx: Reply<SearchResult>,
         embedding: Vec<f32>,
+        /** The text ranks pages with its words higher and picks snippets, it is empty for similar pages. */
+        query: ParsedQuery,
         search_remote: bool,
+        limit: usize,
+        offset: usize,
     },
     ExtractedPage {
         page: ExtractedPage,
         from_network: bool,
     },
+    /** Insert the page, or update it when its content has changed. */
+    UpsertPage {
+        page: ExtractedPage,
+        /** True if the page was new or has changed. */
+        otx: Reply<bool>,
+    },
+    DeletePage {
+        page_id: usize,
+        /** True if the page existed. */
+        otx: Reply<bool>,
+    },
+    DeleteUrl {
+        url: String,
+        /** True if a page with this URL existed. */
+        otx: Reply<bool>,
+    },
+    /** Remember the embedding of a page of a peer, for the next MoreLikeSearch on it. */
+    CacheEmbedding {
+        instance_id: String,
+        page_id: usize,
+        embedding: Vec<f32>,
+    },
+    /** The embedding of one of our own pages, for peers. */
     GetEmbedding {
         page_id: usize,
-        otx: tokio::sync::oneshot::Sender<Vec<f32>>,
+        otx: Reply<Vec<f32>>,
+    },
+    /** The embedding of the text of a query, operators are ignored. */
+    QueryEmbedding {
+        query: String,
+        otx: Reply<Vec<f32>>,
+    },
+    /** The embedding of a page, which may be a page of a peer. */
+    PageEmbedding {
+        instance_id: String,
+        page_id: usize,
+        otx: Reply<Vec<f32>>,
     },
     Stats {
-        otx: tokio::sync::oneshot::Sender<SearchStats>,
+        otx: Reply<SearchStats>,
+    },
+    /** Completions for what the user is typing, popular queries first and then page titles. */
+    Suggest {
+        prefix: String,
+        otx: Reply<Vec<String>>,
+    },
+    /** Group the results of a search into topics, 'query' is left out of their labels. */
+    Topics {
+        pages: Vec<FoundPage>,
+        query: String,
+        otx: Reply<Vec<Topic>>,
     },
     Save,
     Shutdown,
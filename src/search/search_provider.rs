@@ -17,38 +17,129 @@
    along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::config::Config;
+use crate::embedding::embedding_model::{default_model_identity, ModelSettings, DEFAULT_DIMENSION};
+use crate::search::embedding_cache::CacheStats;
+use crate::search::eviction::{eviction_policy, EvictionPolicy};
 use crate::search::page_source::ExtractedPage;
-use crate::search::vector::{bytes_to_embedding, is_normalized, vector_embedding_to_bytes, EM_LEN};
-use crate::util::default_progress_bar;
+use crate::search::query::SearchFilter;
+use crate::search::vector::{is_normalized, Distance, EmbeddingStorage};
+use crate::util::{content_hash, default_progress_bar, host_of, now};
 use anyhow::anyhow;
 use anyhow::bail;
 use cxx::UniquePtr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::iter::zip;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 use std::usize;
 use std::{self, fs};
 use tokio_util::sync::CancellationToken;
 use usearch::ffi::{new_index, Index, IndexOptions, MetricKind, ScalarKind};
 
-// Remove the index when you change any of these values!
-const INDEX_OPTIONS: IndexOptions = IndexOptions {
-    dimensions: EM_LEN,
-    metric: MetricKind::IP,
-    quantization: ScalarKind::F32,
-    connectivity: 0,
-    expansion_add: 0,
-    expansion_search: 0,
-};
-
-#[derive(Debug)]
+/**
+ * Settings the index was built with. They are stored in the metadata next to the index,
+ * and the index is rebuilt when they no longer match the config. Zero means the usearch default.
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct IndexSettings {
+    quantization: String,
+    connectivity: usize,
+    expansion_add: usize,
+    expansion_search: usize,
+    /** How labels map to pages, see LABEL_FORMAT. Indexes from before passages have 0. */
+    #[serde(default)]
+    label_format: u32,
+}
+
+impl IndexSettings {
+    fn from_config(config: &Config) -> IndexSettings {
+        IndexSettings {
+            quantization: config.index_quantization.clone(),
+            connectivity: config.hnsw_connectivity,
+            expansion_add: config.hnsw_expansion_add,
+            expansion_search: config.hnsw_expansion_search,
+            label_format: LABEL_FORMAT,
+        }
+    }
+
+    fn index_options(&self, dimensions: usize) -> Result<IndexOptions, anyhow::Error> {
+        Ok(IndexOptions {
+            dimensions,
+            metric: MetricKind::IP,
+            quantization: scalar_kind(&self.quantization)?,
+            connectivity: self.connectivity,
+            expansion_add: self.expansion_add,
+            expansion_search: self.expansion_search,
+        })
+    }
+}
+
+fn scalar_kind(name: &str) -> Result<ScalarKind, anyhow::Error> {
+    Ok(match name {
+        "f32" => ScalarKind::F32,
+        "f16" => ScalarKind::F16,
+        // usearch calls its 8 bit quantization F8.
+        "i8" => ScalarKind::F8,
+        _ => bail!("Unknown index quantization: {}", name),
+    })
+}
+
+/** Version of the database schema, stored in PRAGMA user_version. */
+const SCHEMA_VERSION: usize = 5;
+
+/**
+ * A label in the index is the page id shifted left by PASSAGE_BITS, plus the passage number.
+ * Passage 0 is the title and start of the page, its embedding is the one in the page table.
+ */
+const LABEL_FORMAT: u32 = 1;
+const PASSAGE_BITS: u32 = 8;
+const MAX_PASSAGES: usize = 1 << PASSAGE_BITS;
+/** A page can match with several passages, so we ask the index for this many times more results. */
+const PASSAGE_OVERFETCH: usize = 4;
+
+/** Pages with less text than this are never duplicates because of their text alone. */
+const MIN_DUPLICATE_TEXT_LEN: usize = 500;
+
+/** How much a full text match can lower the distance of a page. */
+const LEXICAL_WEIGHT: f32 = 0.1;
+/**
+ * Rebuild the index on startup when more than 1 in this many labels belongs to a deleted
+ * page. Searches ask the index for more results to make up for them.
+ */
+const COMPACT_FRACTION: usize = 10;
+/**
+ * A rebuild while running holds up all searches, so then we wait until 1 in this many labels
+ * belongs to a deleted page.
+ */
+const RUNNING_COMPACT_FRACTION: usize = 2;
+/** When we are full, evict 1 in this many pages at once. */
+const EVICT_FRACTION: usize = 100;
+/** A filtered search asks the index for this many times more results than needed. */
+const FILTER_OVERFETCH: usize = 10;
+/**
+ * When deleted pages or the filter left too few results, the index is asked again for twice
+ * as many, up to this many times in total.
+ */
+const SEARCH_ROUNDS: usize = 3;
+
+/** Stored next to the index file, describes what has been added to the index. */
+#[derive(Debug, Deserialize, Serialize)]
+struct IndexMeta {
+    /** All pages up to and including this id are in the index. */
+    max_indexed_id: u64,
+    settings: IndexSettings,
+}
+
+#[derive(Debug, Serialize)]
 pub struct SearchResult {
     pub pages: Vec<FoundPage>,
     pub servers_contacted: usize,
     pub pages_searched: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct FoundPage {
     pub instance_id: String,
     pub page_id: usize,
@@ -56,11 +147,22 @@ pub struct FoundPage {
     pub url: String,
     pub title: String,
     pub text: String,
+    /** When the page was fetched, in seconds since the epoch. 0 if unknown. */
+    pub fetched: u64,
+    /** Three letter language code, empty if unknown. */
+    pub language: String,
+    pub host: String,
+    /** Number of other URLs with the same content that we did not store. */
+    pub duplicates: usize,
+    /** When we indexed the page, in seconds since the epoch. 0 if unknown, like for pages of peers. */
+    pub inserted: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct SearchStats {
     pub pages_indexed: usize,
+    /** The cache belongs to the SearchService, which fills this in. */
+    pub embedding_cache: CacheStats,
 }
 
 pub struct SearchProvider {
@@ -68,17 +170,43 @@ pub struct SearchProvider {
 
     sqlite: rusqlite::Connection,
 
+    /**
+     * Number of labels in the index of which the page was deleted. Our version of usearch
+     * can't remove labels, so these stay in the index until it is rebuilt.
+     */
+    deleted_labels: usize,
+    /** Highest page id that has been added to the index. */
+    max_indexed_id: u64,
+
+    index_settings: IndexSettings,
+    embedding_storage: EmbeddingStorage,
+    max_pages: usize,
+    /** In bytes, 0 for no limit. */
+    max_disk_size: u64,
+    /** Length of the embeddings of the configured model. */
+    dimension: usize,
+    /** What to remove when we are full. None means we stop accepting pages. */
+    eviction: Option<Box<dyn EvictionPolicy>>,
+    /** Pages closer than this to an existing page are stored as a duplicate of it. */
+    duplicate_distance: f32,
+
     shutdown_token: CancellationToken,
     data_dir: String,
 }
 
 impl SearchProvider {
     pub fn new(
-        data_dir: String,
+        config: &Config,
         shutdown_token: CancellationToken,
     ) -> Result<SearchProvider, anyhow::Error> {
+        let data_dir = config.data_dir.clone();
+        let index_settings = IndexSettings::from_config(config);
+        let embedding_storage = EmbeddingStorage::parse(&config.embedding_storage)?;
+        let model = ModelSettings::from_config(config);
+
         // Database
-        let sqlite = rusqlite::Connection::open(Path::new(&data_dir).join("dawnsearch.sqlite"))?;
+        let mut sqlite =
+            rusqlite::Connection::open(Path::new(&data_dir).join("dawnsearch.sqlite"))?;
 
         // Create DB structure
         sqlite.execute(
@@ -97,59 +225,192 @@ impl SearchProvider {
         ",
             (),
         )?;
+        // Ids of deleted pages that may still be in the index. They are never reused.
+        sqlite.execute(
+            "CREATE TABLE IF NOT EXISTS deleted_label (id INTEGER PRIMARY KEY)",
+            (),
+        )?;
+        migrate_schema(&mut sqlite)?;
+        check_embedding_model(&sqlite, &model)?;
+
+        let deleted_labels = sqlite.query_row(
+            "SELECT coalesce(sum(labels), 0) FROM deleted_label",
+            (),
+            |row| row.get::<_, usize>(0),
+        )?;
+
+        // Full text index, kept in sync with the page table by triggers.
+        let fts_exists = sqlite.query_row(
+            "SELECT count(*) FROM sqlite_master WHERE name = 'page_fts'",
+            (),
+            |row| row.get::<_, usize>(0),
+        )? > 0;
+        sqlite.execute_batch(
+            "
+            CREATE VIRTUAL TABLE IF NOT EXISTS page_fts USING fts5(
+                title, text, content='page', content_rowid='id'
+            );
+            CREATE TRIGGER IF NOT EXISTS page_fts_insert AFTER INSERT ON page BEGIN
+                INSERT INTO page_fts(rowid, title, text) VALUES (new.id, new.title, new.text);
+            END;
+            CREATE TRIGGER IF NOT EXISTS page_fts_delete AFTER DELETE ON page BEGIN
+                INSERT INTO page_fts(page_fts, rowid, title, text) VALUES ('delete', old.id, old.title, old.text);
+            END;
+            CREATE TRIGGER IF NOT EXISTS page_fts_update AFTER UPDATE OF title, text ON page BEGIN
+                INSERT INTO page_fts(page_fts, rowid, title, text) VALUES ('delete', old.id, old.title, old.text);
+                INSERT INTO page_fts(rowid, title, text) VALUES (new.id, new.title, new.text);
+            END;
+        ",
+        )?;
+        if !fts_exists {
+            println!("[Search Provider] Building full text index");
+            sqlite.execute("INSERT INTO page_fts(page_fts) VALUES ('rebuild')", ())?;
+        }
+
+        let eviction = eviction_policy(&config.eviction, model.dimension)?;
+        if let Some(e) = &eviction {
+            e.init(&sqlite)?;
+        }
 
         // Index
-        let index = new_index(&INDEX_OPTIONS)?;
+        let index = new_index(&index_settings.index_options(model.dimension)?)?;
 
         let mut search_provider = SearchProvider {
             index,
             sqlite,
+            deleted_labels,
+            max_indexed_id: 0,
+            index_settings,
+            dimension: model.dimension,
+            embedding_storage,
+            max_pages: config.max_pages,
+            max_disk_size: config.max_disk_size_mb as u64 * 1024 * 1024,
+            eviction,
+            duplicate_distance: config.duplicate_distance as f32,
             shutdown_token: shutdown_token.clone(),
             data_dir: data_dir.clone(),
         };
 
-        let index_path_path = Path::new(&data_dir).join("index.usearch");
+        let index_path_path = search_provider.index_path();
         let index_path = index_path_path
             .to_str()
             .ok_or(anyhow!("Could not convert path to string"))?;
-        if !fs::metadata(index_path).is_ok() || !search_provider.index.load(index_path).is_ok() {
-            search_provider.fill_index_from_db()?;
-            search_provider.index.save(index_path)?;
-        } else {
-            println!("[Search Provider] Loaded {}", index_path);
+        search_provider.convert_embeddings()?;
+
+        match search_provider.load_meta() {
+            Some(meta) if meta.settings != search_provider.index_settings => {
+                // The index file was built differently, we can't (or shouldn't) load it.
+                println!(
+                    "[Search Provider] Index was built with {:?} instead of {:?}, rebuilding",
+                    meta.settings, search_provider.index_settings
+                );
+                search_provider.rebuild_index()?;
+            }
+            Some(meta)
+                if fs::metadata(index_path).is_ok()
+                    && search_provider.index.load(index_path).is_ok() =>
+            {
+                println!("[Search Provider] Loaded {}", index_path);
+                // We may have crashed after adding pages to the database but before saving the index.
+                search_provider.max_indexed_id = meta.max_indexed_id;
+                if search_provider.fill_index_from_db(meta.max_indexed_id)? > 0 {
+                    search_provider.save()?;
+                }
+            }
+            _ => {
+                // Without the metadata we don't know what is in the index.
+                println!("[Search Provider] No usable index at {}", index_path);
+                search_provider.rebuild_index()?;
+            }
         }
 
+        search_provider.compact_above(COMPACT_FRACTION)?;
         search_provider.verify()?;
 
         Ok(search_provider)
     }
 
-    fn fill_index_from_db(&mut self) -> Result<(), anyhow::Error> {
+    /** Rebuild the index if it has too many deleted labels, see RUNNING_COMPACT_FRACTION. */
+    pub fn compact(&mut self) -> Result<(), anyhow::Error> {
+        self.compact_above(RUNNING_COMPACT_FRACTION)
+    }
+
+    fn compact_above(&mut self, fraction: usize) -> Result<(), anyhow::Error> {
+        if self.deleted_labels * fraction > self.index.size() {
+            println!(
+                "[Search Provider] {} deleted labels, rebuilding index",
+                self.deleted_labels
+            );
+            self.rebuild_index()?;
+        }
+        Ok(())
+    }
+
+    /** Create a fresh index from the pages in the database, without any deleted labels. */
+    fn rebuild_index(&mut self) -> Result<(), anyhow::Error> {
+        self.index = new_index(&self.index_settings.index_options(self.dimension)?)?;
+        self.max_indexed_id = 0;
+        self.fill_index_from_db(0)?;
+        self.save()?;
+        self.sqlite.execute("DELETE FROM deleted_label", ())?;
+        self.deleted_labels = 0;
+        Ok(())
+    }
+
+    /**
+     * Add all pages with an id bigger than 'after_id' to the index, with their passages.
+     * Returns the number of pages added.
+     */
+    fn fill_index_from_db(&mut self, after_id: u64) -> Result<usize, anyhow::Error> {
         // Fill from DB
-        let count = self.page_count()?;
+        let count = self.sqlite.query_row(
+            "SELECT count(*) FROM page WHERE id > ?1",
+            (after_id,),
+            |row| row.get::<_, usize>(0),
+        )?;
+        if count == 0 {
+            return Ok(0);
+        }
+        let passage_count = self.sqlite.query_row(
+            "SELECT count(*) FROM passage WHERE page_id > ?1",
+            (after_id,),
+            |row| row.get::<_, usize>(0),
+        )?;
         let progress = default_progress_bar(count);
-        progress.set_prefix("Rebuilding index");
+        progress.set_prefix("Filling index");
 
-        self.index.reserve(count)?;
+        self.index
+            .reserve(self.index.size() + count + passage_count)?;
 
         let mut s = self
             .sqlite
-            .prepare("SELECT id, embedding FROM page")
-            .unwrap();
-        let mut qq = s.query(()).unwrap();
-        while let Some(r) = qq.next().unwrap() {
+            .prepare("SELECT id, embedding FROM page WHERE id > ?1 ORDER BY id")?;
+        let mut passages = self
+            .sqlite
+            .prepare("SELECT number, embedding FROM passage WHERE page_id = ?1")?;
+        let mut qq = s.query((after_id,))?;
+        while let Some(r) = qq.next()? {
             if self.shutdown_token.is_cancelled() {
                 break;
             }
             progress.inc(1);
-            let id: u64 = r.get(0).unwrap();
-            let embedding: Vec<u8> = r.get(1).unwrap();
-            let q: &[f32] = unsafe { bytes_to_embedding(embedding.as_slice().try_into()?)? };
+            let id: u64 = r.get(0)?;
+            let embedding: Vec<u8> = r.get(1)?;
+            let q = EmbeddingStorage::decode(&embedding, self.dimension)?;
 
-            self.index.add(id, q).unwrap();
+            self.index.add(label(id, 0), &q)?;
+            let mut pp = passages.query((id,))?;
+            while let Some(p) = pp.next()? {
+                let number: u64 = p.get(0)?;
+                let embedding: Vec<u8> = p.get(1)?;
+                let q = EmbeddingStorage::decode(&embedding, self.dimension)?;
+                self.index.add(label(id, number), &q)?;
+            }
+            self.max_indexed_id = id;
         }
         progress.finish_and_clear();
-        Ok(())
+        println!("[Search Provider] Added {} pages to the index", count);
+        Ok(count)
     }
 
     fn page_count(&self) -> Result<usize, anyhow::Error> {
@@ -161,8 +422,65 @@ impl SearchProvider {
         Ok(count)
     }
 
-    pub fn local_space_available(&mut self) -> bool {
-        self.page_count().unwrap() < 1000000 // TODO: move to config
+    /**
+     * True if there is room for one more page after the 'pending' pages, which are waiting
+     * for their embeddings. Their size on disk is estimated from the pages we have.
+     */
+    pub fn local_space_available(&mut self, pending: usize) -> Result<bool, anyhow::Error> {
+        let pages = self.page_count()?;
+        if pages + pending >= self.max_pages {
+            return Ok(false);
+        }
+        if self.max_disk_size == 0 {
+            return Ok(true);
+        }
+        let usage = self.disk_usage();
+        let per_page = usage / pages.max(1) as u64;
+        Ok(usage + per_page * (pending as u64) < self.max_disk_size)
+    }
+
+    /**
+     * Make room for a new page if we are full and have an eviction policy. 'pending' pages
+     * are waiting for their embeddings and will be inserted first.
+     * Returns true if there is space available.
+     */
+    pub fn make_space(&mut self, pending: usize) -> Result<bool, anyhow::Error> {
+        if self.local_space_available(pending)? {
+            return Ok(true);
+        }
+        let Some(eviction) = &self.eviction else {
+            return Ok(false);
+        };
+        // Evict a batch at once, some policies have to look at all pages.
+        let count = (self.page_count()? / EVICT_FRACTION).max(pending + 1);
+        let ids = eviction.select(&self.sqlite, count)?;
+        println!("[Search Provider] Full, evicting {} pages", ids.len());
+        for id in ids {
+            self.delete(id)?;
+        }
+        self.local_space_available(pending)
+    }
+
+    /**
+     * Bytes used by the database and the index. Space freed by deleting pages is
+     * not counted, SQLite reuses it.
+     */
+    fn disk_usage(&self) -> u64 {
+        let pragma = |name: &str| {
+            self.sqlite
+                .query_row(&format!("PRAGMA {}", name), (), |row| row.get::<_, u64>(0))
+                .unwrap_or(0)
+        };
+        let database = (pragma("page_count") - pragma("freelist_count")) * pragma("page_size");
+
+        // Deleted labels stay in the index file until it is rebuilt.
+        let size = self.index.size().max(1) as u64;
+        let live = size.saturating_sub(self.deleted_labels as u64);
+        let index = fs::metadata(self.index_path())
+            .map(|m| m.len() * live / size)
+            .unwrap_or(0);
+
+        database + index
     }
 
     pub fn shutdown(&mut self) -> anyhow::Result<()> {
@@ -170,73 +488,135 @@ impl SearchProvider {
         Ok(())
     }
 
+    /**
+     * Save the index and its metadata. Both are written to a temporary file first,
+     * so a crash while saving leaves the previous version intact.
+     */
     pub fn save(&mut self) -> anyhow::Result<()> {
-        let index_path_path = Path::new(&self.data_dir).join("index.usearch");
-        let path = index_path_path
+        let index_path = self.index_path();
+        let path = index_path
             .to_str()
             .ok_or(anyhow!("Could not convert index path"))?;
-        self.index.save(path)?;
+        let tmp_path = format!("{}.tmp", path);
+        self.index.save(&tmp_path)?;
+        fs::rename(&tmp_path, path)?;
+
+        let meta = serde_json::to_string(&IndexMeta {
+            max_indexed_id: self.max_indexed_id,
+            settings: self.index_settings.clone(),
+        })?;
+        let meta_path = self.meta_path();
+        let tmp_meta_path = meta_path.with_extension("json.tmp");
+        fs::write(&tmp_meta_path, meta)?;
+        fs::rename(&tmp_meta_path, &meta_path)?;
         println!("[Search Provider] Saved index to {}", path);
         Ok(())
     }
 
-    pub fn embedding_for_page(&self, id: usize) -> Result<Vec<f32>, anyhow::Error> {
+    fn load_meta(&self) -> Option<IndexMeta> {
+        let meta = fs::read_to_string(self.meta_path()).ok()?;
+        serde_json::from_str(&meta).ok()
+    }
+
+    fn index_path(&self) -> PathBuf {
+        Path::new(&self.data_dir).join("index.usearch")
+    }
+
+    fn meta_path(&self) -> PathBuf {
+        Path::new(&self.data_dir).join("index.meta.json")
+    }
+
+    /** None when there is no page with this id. */
+    pub fn embedding_for_page(&self, id: usize) -> Result<Option<Vec<f32>>, anyhow::Error> {
         let mut s = self
             .sqlite
             .prepare("SELECT embedding FROM page WHERE id  = ?1")?;
         let mut qq = s.query(&[&id])?;
         if let Some(r) = qq.next()? {
             let embedding_bytes: Vec<u8> = r.get(0)?;
-            let embedding = unsafe { bytes_to_embedding(embedding_bytes.as_slice().try_into()?)? };
+            let embedding = EmbeddingStorage::decode(&embedding_bytes, self.dimension)?;
 
-            return Ok(embedding.to_vec());
+            return Ok(Some(embedding.to_vec()));
         }
-        bail!("Page not found in DB: {}", id);
+        Ok(None)
     }
 
-    pub fn search_like(&self, id: usize) -> Result<SearchResult, anyhow::Error> {
-        let embedding = self.embedding_for_page(id)?;
-        return self.search_embedding(&embedding.to_vec());
+    pub fn search_like(&self, id: usize, count: usize) -> Result<SearchResult, anyhow::Error> {
+        let embedding = self
+            .embedding_for_page(id)?
+            .ok_or_else(|| anyhow!("Page not found in DB: {}", id))?;
+        return self.search_embedding(&embedding.to_vec(), count, &SearchFilter::default());
     }
 
+    /**
+     * Find the 'count' pages closest to the query embedding that match the filter. A page is
+     * as close as its best matching passage, which is returned as the text of the page.
+     *
+     * The index can't filter, so for a filtered search we ask it for more results and drop the
+     * ones that don't match. A very restrictive filter can therefore return fewer results.
+     */
     pub fn search_embedding(
         &self,
         query_embedding: &Vec<f32>,
+        count: usize,
+        filter: &SearchFilter,
     ) -> Result<SearchResult, anyhow::Error> {
-        if !is_normalized(query_embedding.as_slice().try_into()?) {
+        if query_embedding.len() != self.dimension {
+            bail!(
+                "Search vector has length {} instead of {}",
+                query_embedding.len(),
+                self.dimension
+            );
+        }
+        if !is_normalized(query_embedding) {
             bail!("Search vector is not normalized");
         }
         let mut pages = Vec::new();
 
         let start = Instant::now();
 
-        // Read back the tags
-        let results = self.index.search(query_embedding, 20)?;
-
-        let duration = start.elapsed();
-
-        let mut s = self
-            .sqlite
-            .prepare("SELECT id, url, title, text FROM page WHERE id  = ?1")?;
-        for (distance, id) in zip(results.distances, results.labels) {
-            let mut qq = s.query(&[&id])?;
-            if let Some(r) = qq.next()? {
-                let id: u64 = r.get(0)?;
-                let url = r.get(1)?;
-                let title = r.get(2)?;
-                let text: String = r.get(3)?;
+        let fetch = if filter.is_empty() {
+            count * PASSAGE_OVERFETCH
+        } else {
+            count * PASSAGE_OVERFETCH * FILTER_OVERFETCH
+        };
+        // Labels of deleted pages are skipped below. Ask for more in proportion to how many of
+        // them there are, and ask again for more when that was not enough. The index is rebuilt
+        // long before half of it is deleted, so twice as many is plenty for the first round.
+        let size = self.index.size();
+        let live = size.saturating_sub(self.deleted_labels).max(1);
+        let mut k = fetch
+            .saturating_mul(size)
+            .div_ceil(live)
+            .clamp(fetch, fetch.saturating_mul(2));
+        for round in 0..SEARCH_ROUNDS {
+            let results = self.index.search(query_embedding, k)?;
 
-                pages.push(FoundPage {
-                    instance_id: String::new(),
-                    page_id: id as usize,
-                    distance,
-                    url,
-                    title,
-                    text,
-                });
-            } else {
-                println!("Page not found in DB: {}", id);
+            // Results are sorted by distance, so the first label of a page is its best passage.
+            // After a crash the same label can also be in the index twice.
+            pages.clear();
+            let mut seen = HashSet::new();
+            for (distance, label) in zip(results.distances, results.labels) {
+                let (id, passage) = page_of_label(label);
+                if !seen.insert(id) {
+                    continue;
+                }
+                if let Some(page) = self.found_page(id, passage, distance)? {
+                    if filter.matches(&page) {
+                        pages.push(page);
+                    }
+                }
+            }
+            if pages.len() >= count || k >= size || round + 1 == SEARCH_ROUNDS {
+                break;
             }
+            k = k.saturating_mul(2);
+        }
+        let duration = start.elapsed();
+        pages.truncate(count);
+        if let Some(eviction) = &self.eviction {
+            let ids: Vec<usize> = pages.iter().map(|p| p.page_id).collect();
+            eviction.returned(&self.sqlite, &ids)?;
         }
         println!("Search completed in {} us", duration.as_micros(),);
 
@@ -247,44 +627,420 @@ impl SearchProvider {
         })
     }
 
-    pub fn insert(&mut self, page: ExtractedPage, q: Vec<f32>) -> Result<(), anyhow::Error> {
-        if !self.local_space_available() {
+    /** Titles of our pages that contain the words of 'prefix', best matches first. */
+    pub fn titles_matching(
+        &self,
+        prefix: &str,
+        count: usize,
+    ) -> Result<Vec<String>, anyhow::Error> {
+        let Some(fts_query) = fts_prefix_query(prefix) else {
+            return Ok(Vec::new());
+        };
+        let mut s = self.sqlite.prepare(
+            "SELECT title FROM page_fts WHERE page_fts MATCH ?1 ORDER BY bm25(page_fts) LIMIT ?2",
+        )?;
+        let titles: Vec<String> = s
+            .query_map((&fts_query, count), |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(titles)
+    }
+
+    /**
+     * Search on both meaning and the literal words of the query.
+     *
+     * Pages that match the query in the full text index get their distance lowered by at most
+     * LEXICAL_WEIGHT, so exact matches on names, product codes etc. rise to the top. The result
+     * is still expressed as a distance, so it can be merged with the results from the network.
+     */
+    pub fn search_text(
+        &self,
+        query: &str,
+        query_embedding: &Vec<f32>,
+        count: usize,
+        filter: &SearchFilter,
+    ) -> Result<SearchResult, anyhow::Error> {
+        let mut result = self.search_embedding(query_embedding, count, filter)?;
+        let Some(fts_query) = fts_query(query) else {
+            return Ok(result);
+        };
+
+        let mut s = self.sqlite.prepare(
+            "SELECT rowid, bm25(page_fts) FROM page_fts WHERE page_fts MATCH ?1 ORDER BY bm25(page_fts) LIMIT ?2",
+        )?;
+        let fetch = if filter.is_empty() {
+            count
+        } else {
+            count * FILTER_OVERFETCH
+        };
+        let lexical: Vec<(u64, f64)> = s
+            .query_map((&fts_query, fetch), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        // BM25 scores are negative, lower is better.
+        let best_score = match lexical.first() {
+            Some((_, score)) if *score < 0.0 => *score,
+            _ => return Ok(result),
+        };
+
+        for (id, score) in lexical {
+            let boost = LEXICAL_WEIGHT * (score / best_score) as f32;
+            if let Some(page) = result.pages.iter_mut().find(|p| p.page_id == id as usize) {
+                page.distance -= boost;
+                continue;
+            }
+            // Only found through the full text index, calculate the distance ourselves.
+            let Some(embedding) = self.embedding_for_page(id as usize)? else {
+                continue;
+            };
+            let distance = 1.0 - query_embedding.distance_ip(&embedding);
+            if let Some(page) = self.found_page(id, 0, distance - boost)? {
+                if filter.matches(&page) {
+                    result.pages.push(page);
+                }
+            }
+        }
+
+        result
+            .pages
+            .sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        result.pages.truncate(count);
+        Ok(result)
+    }
+
+    /** The text of the page starts at the given passage, so that is what is shown. */
+    fn found_page(
+        &self,
+        id: u64,
+        passage: u64,
+        distance: f32,
+    ) -> Result<Option<FoundPage>, anyhow::Error> {
+        let mut s = self.sqlite.prepare_cached(
+            "SELECT url, title, text, fetched, language, host,
+                (SELECT count(*) FROM page_duplicate WHERE page_duplicate.page_id = page.id),
+                (SELECT start FROM passage WHERE passage.page_id = page.id AND number = ?2),
+                inserted
+            FROM page WHERE id = ?1",
+        )?;
+        let mut qq = s.query((id, passage))?;
+        if let Some(r) = qq.next()? {
+            let text: String = r.get(2)?;
+            let start: Option<usize> = r.get(7)?;
+            let text = match start.and_then(|start| text.get(start..)) {
+                Some(passage_text) => passage_text.to_string(),
+                None => text,
+            };
+            return Ok(Some(FoundPage {
+                instance_id: String::new(),
+                page_id: id as usize,
+                distance,
+                url: r.get(0)?,
+                title: r.get(1)?,
+                text,
+                fetched: r.get(3)?,
+                language: r.get(4)?,
+                host: r.get(5)?,
+                duplicates: r.get(6)?,
+                inserted: r.get(8)?,
+            }));
+        }
+        Ok(None)
+    }
+
+    /**
+     * Insert a page with the embeddings of ExtractedPage::embedding_texts, the first one is
+     * that of the page itself, the others are those of its passages.
+     */
+    pub fn insert(
+        &mut self,
+        page: ExtractedPage,
+        embeddings: Vec<Vec<f32>>,
+    ) -> Result<(), anyhow::Error> {
+        if !self.local_space_available(0)? {
             bail!("No space available");
         }
-        let mut find_by_url = self
-            .sqlite
-            .prepare("SELECT id FROM page WHERE url = ?1")
-            .unwrap();
-        let found = find_by_url.query_row(&[&page.url], |row| row.get::<_, u64>(0));
+        let found =
+            self.sqlite
+                .query_row("SELECT id FROM page WHERE url = ?1", &[&page.url], |row| {
+                    row.get::<_, u64>(0)
+                });
         if found.is_ok() {
             // Already exists!
             println!("Already have with id {}", page.url);
             return Ok(());
         }
 
-        if !is_normalized(q.as_slice().try_into()?) {
-            bail!("Insert embedding is not normalized");
+        let passages = page.passages();
+        if embeddings.len() != passages.len() + 1 {
+            bail!(
+                "Got {} embeddings for a page with {} passages",
+                embeddings.len(),
+                passages.len() + 1
+            );
+        }
+        for q in &embeddings {
+            if q.len() != self.dimension {
+                bail!(
+                    "Insert embedding has length {} instead of {}",
+                    q.len(),
+                    self.dimension
+                );
+            }
+            if !is_normalized(q) {
+                bail!("Insert embedding is not normalized");
+            }
+        }
+        let q = &embeddings[0];
+
+        if let Some((original_id, distance)) = self.find_duplicate(&page, q)? {
+            println!(
+                "Duplicate of {} at distance {:.3}: {}",
+                original_id, distance, page.url
+            );
+            self.sqlite.execute(
+                "INSERT OR REPLACE INTO page_duplicate (url, page_id, distance) VALUES (?1, ?2, ?3)",
+                (&page.url, original_id, distance),
+            )?;
+            return Ok(());
         }
 
         // Insert into DB
-        let embedding: &[u8; EM_LEN * 4] = unsafe { vector_embedding_to_bytes(&q)? };
-        self.sqlite.execute(
-            "INSERT INTO page (url, title, text, embedding) VALUES (?1, ?2, ?3, ?4)",
-            (page.url, page.title, page.text, embedding),
+        let embedding = self.embedding_storage.encode(q)?;
+        let id = self.next_id()?;
+        let tx = self.sqlite.transaction()?;
+        tx.execute(
+            "INSERT INTO page (id, url, title, text, embedding, fetched, language, host, warc_record_id, content_hash, inserted)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            (
+                id,
+                page.url,
+                page.title,
+                page.text,
+                embedding,
+                page.fetched,
+                page.language,
+                page.host,
+                page.warc_record_id,
+                page.content_hash,
+                now(),
+            ),
         )?;
-        let id: u64 = self
-            .sqlite
-            .query_row("SELECT last_insert_rowid()", (), |row| row.get(0))?;
+        for (number, (passage, q)) in zip(&passages, &embeddings[1..])
+            .enumerate()
+            .take(MAX_PASSAGES - 1)
+        {
+            tx.execute(
+                "INSERT INTO passage (page_id, number, start, length, embedding)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                (
+                    id,
+                    number + 1,
+                    passage.offset,
+                    passage.len,
+                    self.embedding_storage.encode(q)?,
+                ),
+            )?;
+        }
+        tx.commit()?;
 
         // Insert into index
-        if self.index.size() == self.index.capacity() {
+        let labels = embeddings.len().min(MAX_PASSAGES);
+        if self.index.size() + labels > self.index.capacity() {
             // Weirdly enough we have to reserve capacity ourselves.
-            self.index.reserve(self.index.size() + 1024)?;
+            self.index.reserve(self.index.size() + labels + 1024)?;
+        }
+        for (number, q) in embeddings.iter().take(labels).enumerate() {
+            self.index.add(label(id, number as u64), q)?;
+        }
+        self.max_indexed_id = self.max_indexed_id.max(id);
+        Ok(())
+    }
+
+    /**
+     * Find a page with another URL that has the same text, or an embedding within
+     * duplicate_distance. Returns its id and distance.
+     */
+    fn find_duplicate(
+        &self,
+        page: &ExtractedPage,
+        q: &[f32],
+    ) -> Result<Option<(u64, f32)>, anyhow::Error> {
+        // Short texts like "Please enable JavaScript" are the same on many different pages.
+        if page.text.len() >= MIN_DUPLICATE_TEXT_LEN {
+            let mut s = self.sqlite.prepare_cached(
+                "SELECT id FROM page WHERE content_hash = ?1 AND url != ?2 LIMIT 1",
+            )?;
+            let mut qq = s.query((&page.content_hash, &page.url))?;
+            if let Some(r) = qq.next()? {
+                return Ok(Some((r.get(0)?, 0.0)));
+            }
+        }
+
+        if self.duplicate_distance <= 0.0 || self.index.size() == 0 {
+            return Ok(None);
+        }
+        // Only compare with the embeddings of whole pages, not with their passages.
+        let nearest = self.index.search(q, PASSAGE_OVERFETCH)?;
+        for (distance, label) in zip(nearest.distances, nearest.labels) {
+            let (id, passage) = page_of_label(label);
+            if passage != 0 {
+                continue;
+            }
+            // The label could belong to a deleted page.
+            if distance < self.duplicate_distance && self.found_page(id, 0, distance)?.is_some() {
+                return Ok(Some((id, distance)));
+            }
+            break;
+        }
+        Ok(None)
+    }
+
+    /**
+     * Insert the page, or replace it if we already have a page with the same URL.
+     * The embeddings should be calculated from the new content of the page.
+     */
+    pub fn upsert(
+        &mut self,
+        page: ExtractedPage,
+        embeddings: Vec<Vec<f32>>,
+    ) -> Result<(), anyhow::Error> {
+        let replaced = self.delete_by_url(&page.url)?;
+        if !replaced && !self.make_space(0)? {
+            bail!("No space available");
+        }
+        self.insert(page, embeddings)
+    }
+
+    /** Returns true if we don't have this page yet, or if its title or text has changed. */
+    pub fn needs_update(&self, page: &ExtractedPage) -> Result<bool, anyhow::Error> {
+        let mut s = self
+            .sqlite
+            .prepare("SELECT title, text FROM page WHERE url = ?1")?;
+        let mut qq = s.query(&[&page.url])?;
+        if let Some(r) = qq.next()? {
+            let title: String = r.get(0)?;
+            let text: String = r.get(1)?;
+            return Ok(title != page.title || text != page.text);
+        }
+        Ok(true)
+    }
+
+    /** Delete a page. Returns false if there was no page with this id. */
+    pub fn delete(&mut self, id: usize) -> Result<bool, anyhow::Error> {
+        let passages = self.sqlite.query_row(
+            "SELECT count(*) FROM passage WHERE page_id = ?1",
+            (id,),
+            |row| row.get::<_, usize>(0),
+        )?;
+        let removed = self
+            .sqlite
+            .execute("DELETE FROM page WHERE id = ?1", (id,))?;
+        if removed == 0 {
+            return Ok(false);
+        }
+        // The labels stay in the index, searches skip them because the page is gone.
+        let labels = passages + 1;
+        let inserted = self.sqlite.execute(
+            "INSERT OR IGNORE INTO deleted_label (id, labels) VALUES (?1, ?2)",
+            (id, labels),
+        )?;
+        if inserted > 0 {
+            self.deleted_labels += labels;
+        }
+        Ok(true)
+    }
+
+    /** Delete all pages with this URL. Returns false if there were none. */
+    pub fn delete_by_url(&mut self, url: &str) -> Result<bool, anyhow::Error> {
+        let ids: Vec<usize> = {
+            let mut s = self.sqlite.prepare("SELECT id FROM page WHERE url = ?1")?;
+            let ids = s
+                .query_map(&[url], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            ids
+        };
+        let mut deleted = false;
+        for id in ids {
+            deleted |= self.delete(id)?;
+        }
+        Ok(deleted)
+    }
+
+    /** Ids are never reused, so a deleted label in the index can't point at a new page. */
+    fn next_id(&self) -> Result<u64, anyhow::Error> {
+        let id = self.sqlite.query_row(
+            "SELECT max(
+                coalesce((SELECT max(id) FROM page), 0),
+                coalesce((SELECT max(id) FROM deleted_label), 0)
+            ) + 1",
+            (),
+            |row| row.get::<_, u64>(0),
+        )?;
+        Ok(id)
+    }
+
+    /**
+     * Convert embeddings of pages and passages stored in another format to the configured
+     * format. This only happens once after changing embedding_storage.
+     */
+    fn convert_embeddings(&mut self) -> Result<(), anyhow::Error> {
+        let mut count = 0;
+        for table in ["page", "passage"] {
+            count += self.convert_embeddings_in(table)?;
+        }
+        if count > 0 {
+            println!(
+                "[Search Provider] Converted {} embeddings, run VACUUM on the database to reclaim the space",
+                count
+            );
         }
-        self.index.add(id, &q)?;
         Ok(())
     }
 
+    fn convert_embeddings_in(&mut self, table: &str) -> Result<usize, anyhow::Error> {
+        let byte_len = self.embedding_storage.byte_len(self.dimension);
+        let count = self.sqlite.query_row(
+            &format!(
+                "SELECT count(*) FROM {} WHERE length(embedding) != ?1",
+                table
+            ),
+            (byte_len,),
+            |row| row.get::<_, usize>(0),
+        )?;
+        if count == 0 {
+            return Ok(0);
+        }
+        let progress = default_progress_bar(count);
+        progress.set_prefix("Converting embeddings");
+
+        let tx = self.sqlite.transaction()?;
+        {
+            // The passage table has a composite key, both have a rowid.
+            let mut select = tx.prepare(&format!(
+                "SELECT rowid, embedding FROM {} WHERE length(embedding) != ?1",
+                table
+            ))?;
+            let mut update = tx.prepare(&format!(
+                "UPDATE {} SET embedding = ?1 WHERE rowid = ?2",
+                table
+            ))?;
+            let mut qq = select.query((byte_len,))?;
+            while let Some(r) = qq.next()? {
+                if self.shutdown_token.is_cancelled() {
+                    break;
+                }
+                progress.inc(1);
+                let id: u64 = r.get(0)?;
+                let embedding: Vec<u8> = r.get(1)?;
+                let converted = self
+                    .embedding_storage
+                    .encode(&EmbeddingStorage::decode(&embedding, self.dimension)?)?;
+                update.execute((converted, id))?;
+            }
+        }
+        tx.commit()?;
+        progress.finish_and_clear();
+        Ok(count)
+    }
+
     /** Check if all our data is OK */
     pub fn verify(&mut self) -> anyhow::Result<()> {
         println!(
@@ -305,13 +1061,18 @@ impl SearchProvider {
             progress.inc(1);
             let _id: u64 = r.get(0)?;
             let embedding: Vec<u8> = r.get(1)?;
-            if embedding.len() != EM_LEN * 4 {
+            if ![
+                EmbeddingStorage::F32,
+                EmbeddingStorage::I24,
+                EmbeddingStorage::I16,
+            ]
+            .iter()
+            .any(|f| f.byte_len(self.dimension) == embedding.len())
+            {
                 wrong_length += 1;
                 continue;
             }
-            let q: &[f32] = unsafe { bytes_to_embedding(embedding.as_slice().try_into()?)? };
-            let embedding = q.try_into()?;
-            if !is_normalized(embedding) {
+            if EmbeddingStorage::decode(&embedding, self.dimension).is_err() {
                 not_normalized += 1;
             }
         }
@@ -328,6 +1089,215 @@ impl SearchProvider {
     pub fn stats(&self) -> SearchStats {
         SearchStats {
             pages_indexed: self.page_count().unwrap_or(0),
+            embedding_cache: CacheStats::default(),
+        }
+    }
+}
+
+/**
+ * Bring the database up to SCHEMA_VERSION. A new database starts out with the
+ * original page table at version 0.
+ */
+fn migrate_schema(sqlite: &mut rusqlite::Connection) -> Result<(), anyhow::Error> {
+    let version = sqlite.query_row("PRAGMA user_version", (), |row| row.get::<_, usize>(0))?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "Database schema version {} is newer than this version of DawnSearch supports",
+            version
+        );
+    }
+    if version < 1 {
+        println!("[Search Provider] Migrating database to schema version 1");
+        let tx = sqlite.transaction()?;
+        tx.execute_batch(
+            "
+            ALTER TABLE page ADD COLUMN fetched INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE page ADD COLUMN language TEXT NOT NULL DEFAULT '';
+            ALTER TABLE page ADD COLUMN host TEXT NOT NULL DEFAULT '';
+            ALTER TABLE page ADD COLUMN warc_record_id TEXT NOT NULL DEFAULT '';
+            ALTER TABLE page ADD COLUMN content_hash TEXT NOT NULL DEFAULT '';
+            CREATE INDEX IF NOT EXISTS find_by_content_hash on page(content_hash);
+        ",
+        )?;
+        // We don't know when existing pages were fetched, the rest we can work out.
+        {
+            let mut select = tx.prepare("SELECT id, url, title, text FROM page")?;
+            let mut update = tx.prepare(
+                "UPDATE page SET language = ?1, host = ?2, content_hash = ?3 WHERE id = ?4",
+            )?;
+            let mut qq = select.query(())?;
+            while let Some(r) = qq.next()? {
+                let id: u64 = r.get(0)?;
+                let url: String = r.get(1)?;
+                let title: String = r.get(2)?;
+                let text: String = r.get(3)?;
+                let language = whichlang::detect_language(&format!("{} {}", title, text));
+                let host = host_of(&url);
+                update.execute((language.three_letter_code(), host, content_hash(&text), id))?;
+            }
+        }
+        tx.execute("PRAGMA user_version = 1", ())?;
+        tx.commit()?;
+    }
+    if version < 2 {
+        println!("[Search Provider] Migrating database to schema version 2");
+        // URLs we did not store because their content is (almost) the same as that of another page.
+        sqlite.execute_batch(
+            "
+            BEGIN;
+            CREATE TABLE page_duplicate (
+                url TEXT PRIMARY KEY,
+                page_id INTEGER NOT NULL,
+                distance REAL NOT NULL
+            );
+            CREATE INDEX find_duplicate_by_page on page_duplicate(page_id);
+            CREATE TRIGGER page_duplicate_delete AFTER DELETE ON page BEGIN
+                DELETE FROM page_duplicate WHERE page_id = old.id;
+            END;
+            PRAGMA user_version = 2;
+            COMMIT;
+        ",
+        )?;
+    }
+    if version < 3 {
+        println!("[Search Provider] Migrating database to schema version 3");
+        // The model the embeddings were calculated with. Existing pages were calculated
+        // with the model that used to be hard-wired.
+        let tx = sqlite.transaction()?;
+        tx.execute(
+            "CREATE TABLE embedding_model (identity TEXT NOT NULL, dimension INTEGER NOT NULL)",
+            (),
+        )?;
+        let pages = tx.query_row("SELECT count(*) FROM page", (), |row| {
+            row.get::<_, usize>(0)
+        })?;
+        if pages > 0 {
+            tx.execute(
+                "INSERT INTO embedding_model (identity, dimension) VALUES (?1, ?2)",
+                (default_model_identity(), DEFAULT_DIMENSION),
+            )?;
+        }
+        tx.execute("PRAGMA user_version = 3", ())?;
+        tx.commit()?;
+    }
+    if version < 4 {
+        println!("[Search Provider] Migrating database to schema version 4");
+        // Embeddings of the parts of a page after its start. Existing pages only have
+        // their page embedding. A deleted page can have had several labels in the index.
+        sqlite.execute_batch(
+            "
+            BEGIN;
+            CREATE TABLE passage (
+                page_id INTEGER NOT NULL,
+                number INTEGER NOT NULL,
+                start INTEGER NOT NULL,
+                length INTEGER NOT NULL,
+                embedding BLOB NOT NULL,
+                PRIMARY KEY (page_id, number)
+            );
+            CREATE TRIGGER passage_delete AFTER DELETE ON page BEGIN
+                DELETE FROM passage WHERE page_id = old.id;
+            END;
+            ALTER TABLE deleted_label ADD COLUMN labels INTEGER NOT NULL DEFAULT 1;
+            PRAGMA user_version = 4;
+            COMMIT;
+        ",
+        )?;
+    }
+    if version < 5 {
+        println!("[Search Provider] Migrating database to schema version 5");
+        // When the page was indexed, for feeds. We don't know it for existing pages.
+        sqlite.execute_batch(
+            "
+            BEGIN;
+            ALTER TABLE page ADD COLUMN inserted INTEGER NOT NULL DEFAULT 0;
+            PRAGMA user_version = 5;
+            COMMIT;
+        ",
+        )?;
+    }
+    Ok(())
+}
+
+fn label(page_id: u64, passage: u64) -> u64 {
+    page_id << PASSAGE_BITS | passage
+}
+
+/** The page id and passage number of a label. */
+fn page_of_label(label: u64) -> (u64, u64) {
+    (label >> PASSAGE_BITS, label & (MAX_PASSAGES as u64 - 1))
+}
+
+/**
+ * Embeddings of different models can't be mixed, so a database can only be used with
+ * the model it was created with.
+ */
+fn check_embedding_model(
+    sqlite: &rusqlite::Connection,
+    model: &ModelSettings,
+) -> Result<(), anyhow::Error> {
+    let stored = sqlite.query_row(
+        "SELECT identity, dimension FROM embedding_model",
+        (),
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?)),
+    );
+    match stored {
+        Ok((identity, dimension)) => {
+            if identity != model.identity() || dimension != model.dimension {
+                bail!(
+                    "The database was created with model {} ({} dimensions), but {} ({} dimensions) is configured. Configure that model or use another data_dir.",
+                    identity,
+                    dimension,
+                    model.identity(),
+                    model.dimension
+                );
+            }
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            sqlite.execute(
+                "INSERT INTO embedding_model (identity, dimension) VALUES (?1, ?2)",
+                (model.identity(), model.dimension),
+            )?;
         }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/**
+ * An FTS5 query for titles containing all words of 'prefix', the last one may be incomplete.
+ */
+fn fts_prefix_query(prefix: &str) -> Option<String> {
+    let terms: Vec<String> = prefix
+        .split_whitespace()
+        .map(|t| t.replace('"', ""))
+        .filter(|t| !t.is_empty())
+        .map(|t| format!("title : \"{}\"", t))
+        .collect();
+    if terms.is_empty() {
+        return None;
+    }
+    let mut query = terms.join(" AND ");
+    // After a space the user has finished the last word.
+    if !prefix.ends_with(char::is_whitespace) {
+        query += " *";
+    }
+    Some(query)
+}
+
+/**
+ * Turn a user query into an FTS5 query. Every word is quoted so characters like '-' and ':'
+ * are not interpreted as FTS5 syntax, and any of the words may match.
+ */
+fn fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|t| t.replace('"', ""))
+        .filter(|t| !t.is_empty())
+        .map(|t| format!("\"{}\"", t))
+        .collect();
+    if terms.is_empty() {
+        return None;
     }
+    Some(terms.join(" OR "))
 }
@@ -18,156 +18,290 @@
 */
 
 use crate::config::Config;
-use crate::embedding::embedding_service::EmbeddingMsg;
-use crate::net::udp_service::UdpMsg;
+use crate::embedding::embedding_service::{EmbeddingError, EmbeddingMsg, EmbeddingSender};
+use crate::net::udp_service::{NetworkSearchResult, UdpError, UdpMsg};
 use crate::search::best_results::BestResults;
 use crate::search::best_results::NodeReference;
-use crate::search::search_msg::SearchMsg;
+use crate::search::embedding_cache::{CacheKey, EmbeddingCache};
+use crate::search::query::{parse_query, ParsedQuery};
 use crate::search::search_msg::SearchMsg::*;
+use crate::search::search_msg::MAX_RESULTS;
+use crate::search::search_msg::{Reply, SearchError, SearchMsg};
 use crate::search::search_provider::FoundPage;
 use crate::search::search_provider::SearchProvider;
 use crate::search::search_provider::SearchResult;
+use crate::search::snippet::{query_terms, snippet};
+use crate::search::topics::{centre, cluster};
+use crate::util::host_of;
+use anyhow::Context;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
 use tokio::sync::oneshot;
+use tokio::sync::oneshot::error::TryRecvError;
 use tokio_util::sync::CancellationToken;
 
+/** Number of completions returned for a Suggest. */
+const MAX_SUGGESTIONS: usize = 8;
+
+/** How often we check for finished embeddings while pages are waiting for them. */
+const PENDING_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/** How often we check for pages to index when none are waiting for their embeddings. */
+const INDEX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/**
+ * The number of pages that can wait for their embeddings, enough to keep every embedding
+ * worker busy with a full batch. The bulk lane of the embedding channel should have room
+ * for all of them, so the search service never blocks on sending them.
+ */
+pub fn max_pending_pages(config: &Config) -> usize {
+    (config.embedding_batch_size * config.embedding_workers).max(1)
+}
+
+/** A page that will be inserted when its embeddings have been calculated. */
+struct PendingInsert {
+    // Not imported, the name is also a SearchMsg variant.
+    page: crate::search::page_source::ExtractedPage,
+    /** The embedding of the page, followed by those of its passages. */
+    orx: oneshot::Receiver<Result<Vec<Vec<f32>>, EmbeddingError>>,
+    /** Set for an UpsertPage, which replaces the page with the same URL. */
+    upsert: Option<Reply<bool>>,
+}
+
 pub struct SearchService {
     pub config: Config,
     pub shutdown_token: CancellationToken,
-    pub search_rx: Receiver<SearchMsg>,
+    /** Shared, so a restarted service receives the messages sent to the previous one. */
+    pub search_rx: Arc<Mutex<Receiver<SearchMsg>>>,
+    /**
+     * Pages to index, only read while there is room for more pages waiting for their
+     * embeddings. Searches on search_rx are never queued behind them.
+     */
+    pub index_rx: Arc<Mutex<Receiver<SearchMsg>>>,
     pub udp_tx: tokio::sync::mpsc::Sender<UdpMsg>,
     pub search_tx: SyncSender<SearchMsg>,
-    pub embedding_tx: SyncSender<EmbeddingMsg>,
+    pub embedding_tx: EmbeddingSender,
 }
 
 impl SearchService {
-    pub fn start(&mut self) {
-        let mut search_provider =
-            match SearchProvider::new(self.config.data_dir.clone(), self.shutdown_token.clone()) {
-                Err(e) => {
-                    println!("Failed to load search provider {}", e);
-                    return;
+    /** Returns when the service is shut down, or with an error when it can't start. */
+    pub fn start(&mut self) -> anyhow::Result<()> {
+        let search_rx = self.search_rx.clone();
+        // A previous instance that panicked poisons the lock, its receiver is still fine.
+        let search_rx = search_rx.lock().unwrap_or_else(PoisonError::into_inner);
+        let index_rx = self.index_rx.clone();
+        let index_rx = index_rx.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut search_provider = SearchProvider::new(&self.config, self.shutdown_token.clone())
+            .context("Failed to load search provider")?;
+        let mut embedding_cache =
+            EmbeddingCache::new(&self.config).context("Failed to load embedding cache")?;
+        println!("[Search] ready");
+        // We never wait for the embeddings of the pages we insert, so searches are not held
+        // up by them and the embedding service can calculate them in batches.
+        let mut pending: VecDeque<PendingInsert> = VecDeque::new();
+        let max_pending = max_pending_pages(&self.config);
+        loop {
+            insert_pending(&mut search_provider, &mut pending);
+            let message = match search_rx.try_recv() {
+                Ok(message) => message,
+                Err(mpsc::TryRecvError::Disconnected) => break,
+                Err(mpsc::TryRecvError::Empty) => {
+                    let page = if pending.len() < max_pending {
+                        index_rx.try_recv().ok()
+                    } else {
+                        None
+                    };
+                    if let Some(page) = page {
+                        page
+                    } else {
+                        let timeout = if pending.is_empty() {
+                            INDEX_POLL_INTERVAL
+                        } else {
+                            PENDING_POLL_INTERVAL
+                        };
+                        match search_rx.recv_timeout(timeout) {
+                            Ok(message) => message,
+                            Err(RecvTimeoutError::Timeout) => continue,
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
                 }
-                Ok(s) => s,
             };
-        println!("[Search] ready");
-        while let Ok(message) = self.search_rx.recv() {
             if self.config.debug > 0 {
                 println!("[Search] Received message {:?}", message);
             }
             match message {
-                TextSearch { otx, query } => {
-                    let (otx2, orx2) = oneshot::channel();
-                    self.embedding_tx
-                        .send(EmbeddingMsg::GetEmbedding {
-                            text: query,
-                            otx: otx2,
-                        })
-                        .unwrap();
-                    let embedding = orx2.blocking_recv().unwrap();
-
-                    let result = match search_provider.search_embedding(&embedding) {
-                        Ok(r) => r,
+                TextSearch {
+                    otx,
+                    query,
+                    limit,
+                    offset,
+                } => {
+                    let count = limit.saturating_add(offset).min(MAX_RESULTS);
+                    let query = parse_query(&query);
+                    let embedding = match self.query_embedding(&mut embedding_cache, &query.text) {
+                        Ok(embedding) => embedding,
                         Err(e) => {
-                            println!("Failed to perform query: {}", e);
-                            SearchResult {
-                                pages: Vec::new(),
-                                pages_searched: 0,
-                                servers_contacted: 0,
-                            }
+                            let _ = otx.send(Err(e.into()));
+                            continue;
                         }
                     };
-                    self.search_remote(result, embedding, otx);
+
+                    match search_provider.search_text(&query.text, &embedding, count, &query.filter)
+                    {
+                        Ok(result) => {
+                            self.search_remote(result, embedding, query, limit, offset, otx)
+                        }
+                        Err(e) => {
+                            let _ = otx.send(Err(e.into()));
+                        }
+                    }
                 }
                 EmbeddingSearch {
                     otx,
                     embedding,
+                    query,
                     search_remote,
+                    limit,
+                    offset,
                 } => {
-                    let result = match search_provider.search_embedding(&embedding) {
-                        Ok(r) => r,
-                        Err(e) => {
-                            println!("Failed to perform query: {}", e);
-                            SearchResult {
-                                pages: Vec::new(),
-                                pages_searched: 0,
-                                servers_contacted: 0,
-                            }
-                        }
-                    };
                     if search_remote {
-                        self.search_remote(result, embedding, otx);
+                        self.search(&search_provider, embedding, query, limit, offset, otx);
                     } else {
-                        otx.send(result).expect("Sending embedding search result");
+                        // A peer is asking, answer with snippets so the results fit in a packet.
+                        let count = limit.saturating_add(offset).min(MAX_RESULTS);
+                        let result = search_local(&search_provider, &embedding, &query, count)
+                            .map(|r| page_of(r, limit, offset))
+                            .map(|mut r| {
+                                add_snippets(&mut r, &query.text);
+                                r
+                            })
+                            .map_err(SearchError::from);
+                        let _ = otx.send(result);
                     }
                 }
                 MoreLikeSearch {
                     otx,
                     instance_id,
                     page_id,
+                    limit,
+                    offset,
                 } => {
                     if instance_id == "" {
-                        if let Ok(embedding) = search_provider.embedding_for_page(page_id) {
-                            let result = match search_provider.search_embedding(&embedding) {
-                                Ok(r) => r,
-                                Err(e) => {
-                                    println!("Failed to perform query: {}", e);
-                                    SearchResult {
-                                        pages: Vec::new(),
-                                        pages_searched: 0,
-                                        servers_contacted: 0,
-                                    }
-                                }
-                            };
-                            self.search_remote(result, embedding, otx);
+                        match local_embedding(&search_provider, page_id) {
+                            Ok(embedding) => self.search(
+                                &search_provider,
+                                embedding,
+                                ParsedQuery::default(),
+                                limit,
+                                offset,
+                                otx,
+                            ),
+                            Err(e) => {
+                                let _ = otx.send(Err(e));
+                            }
                         }
+                    } else if let Some(embedding) =
+                        embedding_cache.get(&CacheKey::remote_page(&instance_id, page_id))
+                    {
+                        self.search(
+                            &search_provider,
+                            embedding,
+                            ParsedQuery::default(),
+                            limit,
+                            offset,
+                            otx,
+                        );
                     } else {
                         // Reference to a peer, ask it for the embedding so we can search for it.
-                        let (otxx, orxx) = oneshot::channel();
+                        let fetch = self.fetch_remote_embedding(instance_id, page_id);
                         let search_tx2 = self.search_tx.clone();
-                        let udp_tx2 = self.udp_tx.clone();
-                        let debug = self.config.debug;
                         tokio::spawn(async move {
-                            udp_tx2
-                                .send(UdpMsg::GetEmbedding {
-                                    instance_id,
-                                    page_id,
-                                    tx: otxx,
-                                })
-                                .await
-                                .unwrap();
-                            let embedding = orxx.await.unwrap();
-                            if debug > 0 {
-                                println!(
-                                    "[Search] Announce: got a vector of length {} back from UDP",
-                                    embedding.len()
-                                );
+                            match fetch.await {
+                                // Pass it back into ourselves as a normal query.
+                                Ok(embedding) => {
+                                    let _ = search_tx2.send(SearchMsg::EmbeddingSearch {
+                                        otx,
+                                        embedding,
+                                        query: ParsedQuery::default(),
+                                        search_remote: true,
+                                        limit,
+                                        offset,
+                                    });
+                                }
+                                Err(e) => {
+                                    let _ = otx.send(Err(e));
+                                }
                             }
-                            // Pass it back into ourselves as a normal query.
-                            search_tx2
-                                .send(SearchMsg::EmbeddingSearch {
-                                    otx,
-                                    embedding,
-                                    search_remote: true,
-                                })
-                                .unwrap();
                         });
                     }
                 }
+                TopicSearch {
+                    otx,
+                    pages,
+                    limit,
+                    offset,
+                } => {
+                    let embeddings =
+                        self.page_embeddings(&search_provider, &mut embedding_cache, &pages);
+                    let search_tx2 = self.search_tx.clone();
+                    tokio::spawn(async move {
+                        let embeddings = embeddings.await;
+                        let Some(embedding) = centre(embeddings.iter().flatten()) else {
+                            let _ = otx.send(Err(SearchError::InvalidRequest(
+                                "None of the pages of the topic were found".to_string(),
+                            )));
+                            return;
+                        };
+                        let _ = search_tx2.send(SearchMsg::EmbeddingSearch {
+                            otx,
+                            embedding,
+                            query: ParsedQuery::default(),
+                            search_remote: true,
+                            limit,
+                            offset,
+                        });
+                    });
+                }
+                Topics { pages, query, otx } => {
+                    let references: Vec<(String, usize)> = pages
+                        .iter()
+                        .map(|p| (p.instance_id.clone(), p.page_id))
+                        .collect();
+                    let embeddings =
+                        self.page_embeddings(&search_provider, &mut embedding_cache, &references);
+                    tokio::spawn(async move {
+                        let embeddings = embeddings.await;
+                        let _ = otx.send(Ok(cluster(pages, embeddings, &query)));
+                    });
+                }
                 ExtractedPage { page, from_network } => {
-                    if search_provider.local_space_available() {
+                    // The pages waiting for their embeddings need room as well.
+                    let space_available =
+                        search_provider
+                            .make_space(pending.len())
+                            .unwrap_or_else(|e| {
+                                eprintln!("Failed to make space {}", e);
+                                false
+                            });
+                    if space_available {
+                        // There is room in the bulk lane, see max_pending_pages.
                         let (otx2, orx2) = oneshot::channel();
-                        self.embedding_tx
-                            .send(EmbeddingMsg::GetEmbedding {
-                                text: page.combined.clone(),
-                                otx: otx2,
-                            })
-                            .unwrap();
-                        let embedding = orx2.blocking_recv().unwrap();
-                        if let Err(e) = search_provider.insert(page.clone(), embedding) {
-                            eprintln!("Failed to insert {}", e);
-                        }
+                        self.embedding_tx.send(EmbeddingMsg::GetEmbeddings {
+                            texts: page.embedding_texts(),
+                            otx: otx2,
+                        });
+                        pending.push_back(PendingInsert {
+                            page: page.clone(),
+                            orx: orx2,
+                            upsert: None,
+                        });
                     }
                     if !from_network {
                         // Insert on the network.
@@ -179,62 +313,299 @@ impl SearchService {
                         });
                     }
                 }
+                UpsertPage { page, otx } => match search_provider.needs_update(&page) {
+                    Ok(true) => {
+                        // Someone is waiting for the reply, so go ahead of indexing.
+                        let (otx2, orx2) = oneshot::channel();
+                        self.embedding_tx
+                            .send_priority(EmbeddingMsg::GetEmbeddings {
+                                texts: page.embedding_texts(),
+                                otx: otx2,
+                            });
+                        pending.push_back(PendingInsert {
+                            page,
+                            orx: orx2,
+                            upsert: Some(otx),
+                        });
+                    }
+                    Ok(false) => {
+                        if self.config.debug > 0 {
+                            println!("[Search] Page unchanged {}", page.url);
+                        }
+                        let _ = otx.send(Ok(false));
+                    }
+                    Err(e) => {
+                        let _ = otx.send(Err(e.into()));
+                    }
+                },
+                DeletePage { page_id, otx } => {
+                    let _ = otx.send(search_provider.delete(page_id).map_err(SearchError::from));
+                }
+                DeleteUrl { url, otx } => {
+                    let _ = otx.send(
+                        search_provider
+                            .delete_by_url(&url)
+                            .map_err(SearchError::from),
+                    );
+                }
+                CacheEmbedding {
+                    instance_id,
+                    page_id,
+                    embedding,
+                } => {
+                    embedding_cache.put(CacheKey::remote_page(&instance_id, page_id), embedding);
+                }
                 Stats { otx } => {
-                    let stats = search_provider.stats();
-                    otx.send(stats).expect("Send response");
+                    let mut stats = search_provider.stats();
+                    stats.embedding_cache = embedding_cache.stats();
+                    let _ = otx.send(Ok(stats));
+                }
+                Suggest { prefix, otx } => {
+                    let mut suggestions = embedding_cache.popular_queries(
+                        &prefix,
+                        MAX_SUGGESTIONS,
+                        self.config.suggest_min_searches,
+                    );
+                    if suggestions.len() < MAX_SUGGESTIONS {
+                        match search_provider.titles_matching(&prefix, MAX_SUGGESTIONS) {
+                            Ok(titles) => {
+                                for title in titles {
+                                    let title = title.trim();
+                                    if suggestions.len() < MAX_SUGGESTIONS
+                                        && !title.is_empty()
+                                        && !suggestions
+                                            .iter()
+                                            .any(|s| s.eq_ignore_ascii_case(title))
+                                    {
+                                        suggestions.push(title.to_string());
+                                    }
+                                }
+                            }
+                            Err(e) => eprintln!("Failed to find titles for {}: {}", prefix, e),
+                        }
+                    }
+                    let _ = otx.send(Ok(suggestions));
                 }
                 GetEmbedding { page_id, otx } => {
-                    let em = search_provider.embedding_for_page(page_id).unwrap();
-                    otx.send(em).expect("Send response");
+                    let _ = otx.send(local_embedding(&search_provider, page_id));
+                }
+                QueryEmbedding { query, otx } => {
+                    let query = parse_query(&query);
+                    let embedding = self.query_embedding(&mut embedding_cache, &query.text);
+                    let _ = otx.send(embedding.map_err(SearchError::from));
+                }
+                PageEmbedding {
+                    instance_id,
+                    page_id,
+                    otx,
+                } => {
+                    if instance_id == "" {
+                        let _ = otx.send(local_embedding(&search_provider, page_id));
+                    } else if let Some(embedding) =
+                        embedding_cache.get(&CacheKey::remote_page(&instance_id, page_id))
+                    {
+                        let _ = otx.send(Ok(embedding));
+                    } else {
+                        let fetch = self.fetch_remote_embedding(instance_id, page_id);
+                        tokio::spawn(async move {
+                            let _ = otx.send(fetch.await);
+                        });
+                    }
                 }
                 Save => {
-                    search_provider.save().unwrap();
+                    if let Err(e) = search_provider.compact() {
+                        eprintln!("Failed to rebuild the index {}", e);
+                    }
+                    if let Err(e) = search_provider.save() {
+                        eprintln!("Failed to save the index {}", e);
+                    }
+                    if let Err(e) = embedding_cache.save() {
+                        eprintln!("Failed to save embedding cache {}", e);
+                    }
                 }
                 Shutdown => {
-                    search_provider.shutdown().unwrap();
+                    // Pages still waiting for their embeddings are dropped, an UpsertPage
+                    // among them is answered with Stopped.
+                    if let Err(e) = search_provider.shutdown() {
+                        eprintln!("Failed to save the index {}", e);
+                    }
+                    if let Err(e) = embedding_cache.save() {
+                        eprintln!("Failed to save embedding cache {}", e);
+                    }
                     break;
                 }
             }
         }
+        Ok(())
+    }
+
+    /** The embedding of the text of a query, from the cache when we can. */
+    fn query_embedding(
+        &self,
+        embedding_cache: &mut EmbeddingCache,
+        text: &str,
+    ) -> Result<Vec<f32>, EmbeddingError> {
+        let cache_key = CacheKey::query(text);
+        if let Some(embedding) = embedding_cache.get(&cache_key) {
+            return Ok(embedding);
+        }
+        let (otx, orx) = oneshot::channel();
+        // Someone is waiting for the results, so go ahead of indexing.
+        self.embedding_tx.send_priority(EmbeddingMsg::GetEmbedding {
+            text: text.to_string(),
+            otx,
+        });
+        let embedding = orx.blocking_recv()??;
+        embedding_cache.put(cache_key, embedding.clone());
+        Ok(embedding)
+    }
+
+    /** Ask a peer for the embedding of one of its pages, and put it in our cache. */
+    fn fetch_remote_embedding(
+        &self,
+        instance_id: String,
+        page_id: usize,
+    ) -> impl Future<Output = Result<Vec<f32>, SearchError>> {
+        let search_tx = self.search_tx.clone();
+        let udp_tx = self.udp_tx.clone();
+        let debug = self.config.debug;
+        async move {
+            let (otx, orx) = oneshot::channel();
+            let message = UdpMsg::GetEmbedding {
+                instance_id: instance_id.clone(),
+                page_id,
+                tx: otx,
+            };
+            udp_tx.send(message).await.map_err(|_| UdpError::Stopped)?;
+            let embedding = orx.await.map_err(UdpError::from)??;
+            if debug > 0 {
+                println!(
+                    "[Search] got a vector of length {} back from UDP",
+                    embedding.len()
+                );
+            }
+            // Only an optimization, so it does not matter when the search service is gone.
+            let _ = search_tx.send(SearchMsg::CacheEmbedding {
+                instance_id,
+                page_id,
+                embedding: embedding.clone(),
+            });
+            Ok(embedding)
+        }
+    }
+
+    /**
+     * The embeddings of pages, None for the ones we can't get. Our own come from the index,
+     * those of peers from the cache or else from the peer, all at the same time.
+     */
+    fn page_embeddings(
+        &self,
+        search_provider: &SearchProvider,
+        embedding_cache: &mut EmbeddingCache,
+        pages: &[(String, usize)],
+    ) -> impl Future<Output = Vec<Option<Vec<f32>>>> {
+        let mut embeddings = Vec::new();
+        let mut fetches = Vec::new();
+        for (instance_id, page_id) in pages {
+            if instance_id.is_empty() {
+                embeddings.push(local_embedding(search_provider, *page_id).ok());
+                fetches.push(None);
+            } else if let Some(embedding) =
+                embedding_cache.get(&CacheKey::remote_page(instance_id, *page_id))
+            {
+                embeddings.push(Some(embedding));
+                fetches.push(None);
+            } else {
+                embeddings.push(None);
+                let fetch = self.fetch_remote_embedding(instance_id.clone(), *page_id);
+                fetches.push(Some(tokio::spawn(fetch)));
+            }
+        }
+        async move {
+            for (embedding, fetch) in embeddings.iter_mut().zip(fetches) {
+                if let Some(fetch) = fetch {
+                    *embedding = fetch.await.ok().and_then(|r| r.ok());
+                }
+            }
+            embeddings
+        }
+    }
+
+    /** Search our own index, then the network. */
+    fn search(
+        &mut self,
+        search_provider: &SearchProvider,
+        embedding: Vec<f32>,
+        query: ParsedQuery,
+        limit: usize,
+        offset: usize,
+        otx: Reply<SearchResult>,
+    ) {
+        let count = limit.saturating_add(offset).min(MAX_RESULTS);
+        match search_local(search_provider, &embedding, &query, count) {
+            Ok(result) => self.search_remote(result, embedding, query, limit, offset, otx),
+            Err(e) => {
+                let _ = otx.send(Err(e.into()));
+            }
+        }
     }
 
+    /** Add the results of our peers. When the network fails, only our own results are returned. */
     fn search_remote(
         &mut self,
-        result: SearchResult,
+        mut result: SearchResult,
         embedding: Vec<f32>,
-        otx: oneshot::Sender<SearchResult>,
+        query: ParsedQuery,
+        limit: usize,
+        offset: usize,
+        otx: Reply<SearchResult>,
     ) {
+        add_snippets(&mut result, &query.text);
         let mut all_found_pages = result.pages;
 
         if self.config.debug > 0 {
             println!("[Search] got {} local results", all_found_pages.len());
         }
 
-        // Store them in a BestResults
-        let mut best = BestResults::new(20);
+        // Store them in a BestResults. To skip 'offset' results we need to know all of them.
+        let count = limit.saturating_add(offset).min(MAX_RESULTS);
+        let mut best = BestResults::new(count);
         for (id, page) in all_found_pages.iter().enumerate() {
             best.insert(NodeReference {
                 id,
                 distance: page.distance,
             });
         }
-        // We now also know what our worst result is.
-        let worst_distance = best.worst_distance();
+        // Peers only need to send pages better than our worst result. Until we have 'count'
+        // results, for example with a filter or a deep page, every page of theirs is welcome.
+        let distance_limit = (best.len() >= count).then(|| best.worst_distance());
 
         let udp_tx2 = self.udp_tx.clone();
         let debug = self.config.debug;
         tokio::spawn(async move {
             // Also fire it off to the network.
             let (otxx, orxx) = oneshot::channel();
-            udp_tx2
-                .send(UdpMsg::Search {
-                    embedding,
-                    distance_limit: Some(worst_distance),
-                    tx: otxx,
-                })
-                .await
-                .unwrap();
-            let r = orxx.await.unwrap();
+            let message = UdpMsg::Search {
+                embedding,
+                distance_limit,
+                count,
+                query: query.text,
+                filter: query.filter.clone(),
+                tx: otxx,
+            };
+            let r = match udp_tx2.send(message).await {
+                Ok(()) => orxx.await.map_err(UdpError::from).and_then(|r| r),
+                Err(_) => Err(UdpError::Stopped),
+            };
+            let r = r.unwrap_or_else(|e| {
+                eprintln!("[Search] Searching the network failed: {}", e);
+                NetworkSearchResult {
+                    results: Vec::new(),
+                    servers_contacted: 0,
+                    servers_responded: 0,
+                    pages_searched: 0,
+                }
+            });
             if debug > 0 {
                 println!(
                     "[Search] remote: got {} search results from UDP",
@@ -245,18 +616,33 @@ impl SearchService {
             // Add our own results to this.
             let total_pages = result.pages_searched;
             for x in r.results {
-                best.insert(NodeReference {
-                    id: all_found_pages.len(),
-                    distance: x.distance,
-                });
-                all_found_pages.push(FoundPage {
+                // Several instances can have the same page.
+                if all_found_pages.iter().any(|p| p.url == x.url) {
+                    continue;
+                }
+                let host = host_of(&x.url);
+                let page = FoundPage {
                     instance_id: x.instance_id,
                     page_id: x.page_id,
                     title: x.title,
                     distance: x.distance,
                     url: x.url,
                     text: x.text,
+                    fetched: x.fetched,
+                    language: x.language,
+                    host,
+                    duplicates: 0,
+                    inserted: 0,
+                };
+                // Peers running an older version don't know about filters.
+                if !query.filter.matches(&page) {
+                    continue;
+                }
+                best.insert(NodeReference {
+                    id: all_found_pages.len(),
+                    distance: page.distance,
                 });
+                all_found_pages.push(page);
             }
 
             // We have collected our results.
@@ -264,15 +650,96 @@ impl SearchService {
             let real_results: Vec<FoundPage> = best
                 .results()
                 .iter()
+                .skip(offset)
+                .take(limit)
                 .map(|nr| all_found_pages[nr.id].clone())
                 .collect();
 
-            otx.send(SearchResult {
+            let _ = otx.send(Ok(SearchResult {
                 pages: real_results,
                 pages_searched: total_pages + r.pages_searched,
                 servers_contacted: r.servers_contacted,
-            })
-            .expect("Send response");
+            }));
         });
     }
 }
+
+/** Insert the pending pages whose embeddings are ready, in the order they arrived. */
+fn insert_pending(search_provider: &mut SearchProvider, pending: &mut VecDeque<PendingInsert>) {
+    while let Some(mut p) = pending.pop_front() {
+        let embeddings = match p.orx.try_recv() {
+            Ok(embeddings) => embeddings,
+            Err(TryRecvError::Empty) => {
+                pending.push_front(p);
+                return;
+            }
+            Err(TryRecvError::Closed) => Err(EmbeddingError::Stopped),
+        };
+        let url = p.page.url.clone();
+        let result = embeddings
+            .map_err(SearchError::from)
+            .and_then(|embeddings| {
+                if p.upsert.is_some() {
+                    search_provider.upsert(p.page, embeddings)?;
+                } else {
+                    search_provider.insert(p.page, embeddings)?;
+                }
+                Ok(())
+            });
+        match p.upsert {
+            Some(otx) => {
+                let _ = otx.send(result.map(|()| true));
+            }
+            None => {
+                if let Err(e) = result {
+                    eprintln!("Failed to insert {}: {}", url, e);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Search our own index. With the text of the query, pages that contain its words rank higher,
+ * also when a peer is asking.
+ */
+fn search_local(
+    search_provider: &SearchProvider,
+    embedding: &Vec<f32>,
+    query: &ParsedQuery,
+    count: usize,
+) -> Result<SearchResult, anyhow::Error> {
+    if query.text.trim().is_empty() {
+        search_provider.search_embedding(embedding, count, &query.filter)
+    } else {
+        search_provider.search_text(&query.text, embedding, count, &query.filter)
+    }
+}
+
+/** The embedding of one of our own pages. */
+fn local_embedding(
+    search_provider: &SearchProvider,
+    page_id: usize,
+) -> Result<Vec<f32>, SearchError> {
+    match search_provider.embedding_for_page(page_id) {
+        Ok(Some(embedding)) => Ok(embedding),
+        Ok(None) => Err(SearchError::PageNotFound(page_id)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/** Only keep the results from 'offset' up to 'offset + limit'. */
+fn page_of(result: SearchResult, limit: usize, offset: usize) -> SearchResult {
+    SearchResult {
+        pages: result.pages.into_iter().skip(offset).take(limit).collect(),
+        ..result
+    }
+}
+
+/** Replace the text of our pages by the part that is most relevant to the query. */
+fn add_snippets(result: &mut SearchResult, query: &str) {
+    let terms = query_terms(query);
+    for page in &mut result.pages {
+        page.text = snippet(&page.text, &terms);
+    }
+}
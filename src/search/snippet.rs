@@ -0,0 +1,275 @@
+/*
+   Copyright 2023 Krol Inventions B.V.
+
+   This file is part of DawnSearch.
+
+   DawnSearch is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   DawnSearch is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::cmp::Reverse;
+
+use crate::search::query::parse_query;
+use crate::util::slice_up_to;
+
+/**
+ * Snippets are at most this many bytes. The instance that has the page picks the snippet,
+ * so together with the URL and title it still fits in a single UDP packet.
+ */
+pub const SNIPPET_LEN: usize = 300;
+
+/** Between sentences that are not next to each other in the page. */
+const GAP: &str = " … ";
+
+/** The lowercase words of a query that we look for in pages, without the operators. */
+pub fn query_terms(query: &str) -> Vec<String> {
+    let query = parse_query(query);
+    let mut terms: Vec<String> = Vec::new();
+    for (_, word) in word_indices(&query.text) {
+        let word = word.to_lowercase();
+        // Single letters match almost every sentence.
+        if word.chars().count() > 1 && !terms.contains(&word) {
+            terms.push(word);
+        }
+    }
+    terms
+}
+
+/** The words in a text with their byte offset. A word is a run of letters and digits. */
+pub fn word_indices(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut start = None;
+    // The extra space ends a word at the end of the text.
+    let mut chars = text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')));
+    std::iter::from_fn(move || {
+        for (i, c) in chars.by_ref() {
+            match (start, c.is_alphanumeric()) {
+                (None, true) => start = Some(i),
+                (Some(s), false) => {
+                    start = None;
+                    return Some((s, &text[s..i]));
+                }
+                _ => {}
+            }
+        }
+        None
+    })
+}
+
+pub fn is_term(word: &str, terms: &[String]) -> bool {
+    !terms.is_empty() && terms.contains(&word.to_lowercase())
+}
+
+/**
+ * The sentences of the text that contain the most query terms, in the order of the page and
+ * at most SNIPPET_LEN long. Without terms, or when none of them occur, it is the start of the
+ * text. That is the passage that matched the query embedding best.
+ */
+pub fn snippet(text: &str, terms: &[String]) -> String {
+    let sentences = sentences(text);
+    let scores: Vec<usize> = sentences
+        .iter()
+        .map(|&(start, end)| score(&text[start..end], terms))
+        .collect();
+
+    // Best sentences first, the earlier one when they are as good.
+    let mut best: Vec<usize> = (0..sentences.len()).filter(|&i| scores[i] > 0).collect();
+    best.sort_by_key(|&i| (Reverse(scores[i]), i));
+    let Some(&first) = best.first() else {
+        return slice_up_to(text.trim_start(), SNIPPET_LEN).to_string();
+    };
+
+    let (start, end) = sentences[first];
+    if end - start >= SNIPPET_LEN {
+        return around_first_term(&text[start..end], terms).to_string();
+    }
+    let mut chosen = vec![first];
+    let mut len = end - start;
+    for &i in &best[1..] {
+        let (start, end) = sentences[i];
+        if len + GAP.len() + end - start <= SNIPPET_LEN {
+            chosen.push(i);
+            len += GAP.len() + end - start;
+        }
+    }
+    chosen.sort();
+
+    let mut r = String::new();
+    for (n, &i) in chosen.iter().enumerate() {
+        if n > 0 {
+            r += if chosen[n - 1] + 1 == i { " " } else { GAP };
+        }
+        r += &text[sentences[i].0..sentences[i].1];
+    }
+    r
+}
+
+/** Number of different terms in the sentence. */
+fn score(sentence: &str, terms: &[String]) -> usize {
+    let mut found: Vec<String> = Vec::new();
+    for (_, word) in word_indices(sentence) {
+        let word = word.to_lowercase();
+        if terms.contains(&word) && !found.contains(&word) {
+            found.push(word);
+        }
+    }
+    found.len()
+}
+
+/** SNIPPET_LEN of a long sentence, starting a bit before the first term. */
+fn around_first_term<'a>(sentence: &'a str, terms: &[String]) -> &'a str {
+    let first = word_indices(sentence)
+        .find(|(_, word)| is_term(word, terms))
+        .map(|(i, _)| i)
+        .unwrap_or(0);
+    // Some words before the term, starting at a word.
+    let mut start = first.saturating_sub(SNIPPET_LEN / 4);
+    while !sentence.is_char_boundary(start) {
+        start -= 1;
+    }
+    if start > 0 {
+        start = word_indices(&sentence[start..])
+            .next()
+            .map(|(i, _)| start + i)
+            .unwrap_or(start);
+    }
+    slice_up_to(&sentence[start..], SNIPPET_LEN)
+}
+
+/**
+ * Byte ranges of the sentences of a text, without surrounding whitespace. A sentence ends
+ * with a period, exclamation or question mark followed by whitespace, or a line break.
+ */
+fn sentences(text: &str) -> Vec<(usize, usize)> {
+    let mut result = Vec::new();
+    let mut push = |start: usize, end: usize| {
+        let s = &text[start..end];
+        let trimmed_start = start + s.len() - s.trim_start().len();
+        let trimmed_end = start + s.trim_end().len();
+        if trimmed_end > trimmed_start {
+            result.push((trimmed_start, trimmed_end));
+        }
+    };
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let end = match c {
+            '\n' => Some(i),
+            '.' | '!' | '?' => match chars.peek() {
+                Some((_, next)) if !next.is_whitespace() => None,
+                _ => Some(i + 1),
+            },
+            _ => None,
+        };
+        if let Some(end) = end {
+            push(start, end);
+            start = end;
+        }
+    }
+    push(start, text.len());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn query_terms_skip_operators_single_letters_and_repeats() {
+        assert_eq!(
+            query_terms("Rust site:example.com a RUST lang:en programming"),
+            terms(&["rust", "programming"])
+        );
+        assert!(query_terms("site:example.com").is_empty());
+    }
+
+    #[test]
+    fn word_indices_are_byte_offsets() {
+        let words: Vec<(usize, &str)> = word_indices("héllo, wörld!").collect();
+        assert_eq!(words, vec![(0, "héllo"), (8, "wörld")]);
+        // The last word ends at the end of the text.
+        let words: Vec<(usize, &str)> = word_indices("end word").collect();
+        assert_eq!(words, vec![(0, "end"), (4, "word")]);
+        assert_eq!(word_indices("").count(), 0);
+        assert_eq!(word_indices("... !").count(), 0);
+    }
+
+    #[test]
+    fn is_term_ignores_case() {
+        assert!(is_term("Rust", &terms(&["rust"])));
+        assert!(!is_term("rusty", &terms(&["rust"])));
+        assert!(!is_term("rust", &[]));
+    }
+
+    #[test]
+    fn sentences_end_at_punctuation_followed_by_whitespace() {
+        let text = "One. Two!\nThree? 3.5 stays";
+        let found: Vec<&str> = sentences(text)
+            .into_iter()
+            .map(|(start, end)| &text[start..end])
+            .collect();
+        assert_eq!(found, vec!["One.", "Two!", "Three?", "3.5 stays"]);
+        assert!(sentences(" \n ").is_empty());
+    }
+
+    #[test]
+    fn without_matches_the_snippet_is_the_start() {
+        let text = "  First sentence. Second one.";
+        assert_eq!(snippet(text, &[]), "First sentence. Second one.");
+        assert_eq!(
+            snippet(text, &terms(&["missing"])),
+            "First sentence. Second one."
+        );
+        assert_eq!(snippet("", &terms(&["rust"])), "");
+    }
+
+    #[test]
+    fn snippet_picks_the_sentences_with_terms() {
+        let text = "Cats are nice. Dogs bark loudly. Rust is a language.";
+        assert_eq!(snippet(text, &terms(&["rust"])), "Rust is a language.");
+        // Sentences keep the order of the page, with a gap between those that are apart.
+        assert_eq!(
+            snippet(text, &terms(&["rust", "cats"])),
+            "Cats are nice. … Rust is a language."
+        );
+        assert_eq!(
+            snippet(text, &terms(&["dogs", "rust"])),
+            "Dogs bark loudly. Rust is a language."
+        );
+    }
+
+    #[test]
+    fn long_sentences_are_cut_around_the_first_term() {
+        let text = "a ".repeat(200) + "target" + &" b".repeat(200);
+        let s = snippet(&text, &terms(&["target"]));
+        assert!(s.len() <= SNIPPET_LEN);
+        assert!(s.contains("target"));
+        // It starts at a word, not in the middle of one.
+        assert!(s.starts_with('a'));
+    }
+
+    #[test]
+    fn long_sentences_are_cut_at_character_boundaries() {
+        // The start before the term falls in the middle of an é.
+        let text = "é".repeat(200) + "  target" + &" more".repeat(100);
+        let s = snippet(&text, &terms(&["target"]));
+        assert!(s.len() <= SNIPPET_LEN);
+        assert!(s.starts_with('é'));
+        assert!(s.contains("target"));
+    }
+}
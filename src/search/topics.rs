@@ -0,0 +1,335 @@
+/*
+   Copyright 2023 Krol Inventions B.V.
+
+   This file is part of DawnSearch.
+
+   DawnSearch is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   DawnSearch is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+
+use crate::search::search_provider::FoundPage;
+use crate::search::snippet::{query_terms, word_indices};
+use crate::search::vector::{distance_cosine, normalize};
+
+/** Results are merged into at most this many topics. */
+pub const MAX_TOPICS: usize = 6;
+
+/** Topics whose centres are closer than this are always merged. */
+const MERGE_DISTANCE: f32 = 0.4;
+
+/** Number of pages used to search for more like a topic, they end up in a link. */
+pub const TOPIC_REFERENCES: usize = 8;
+
+const LABEL_WORDS: usize = 3;
+
+/** Words that say nothing about a topic. */
+const STOP_WORDS: &[&str] = &[
+    "the", "and", "for", "with", "from", "that", "this", "your", "you", "are", "was", "how",
+    "what", "why", "who", "its", "our", "all", "new", "not", "but", "can", "has", "have", "about",
+    "into", "more", "www", "com", "org", "net", "home", "page", "der", "die", "das", "und", "les",
+    "des", "een", "het",
+];
+
+#[derive(Debug)]
+pub struct Topic {
+    /** A few words that the titles of the topic have in common. */
+    pub label: String,
+    /** In the order of the results, the best match first. */
+    pub pages: Vec<FoundPage>,
+    /**
+     * The pages closest to the centre of the topic, as instance and page id. Searching for
+     * their centre finds more like the topic. Empty for the pages we have no embedding of.
+     */
+    pub references: Vec<(String, usize)>,
+}
+
+/** A group of results during clustering. */
+struct Cluster {
+    /** Indexes into the results, ascending. */
+    members: Vec<usize>,
+    /** Normalized mean of the embeddings of the members. */
+    centre: Vec<f32>,
+}
+
+/**
+ * Group the results of a search on their embeddings, which are in the same order as the
+ * pages. Every page starts as its own topic, then the two closest topics are merged until
+ * there are at most MAX_TOPICS and they are not too close together. Pages without an
+ * embedding go into a last topic "Other". The topic with the best match comes first.
+ */
+pub fn cluster(
+    pages: Vec<FoundPage>,
+    embeddings: Vec<Option<Vec<f32>>>,
+    query: &str,
+) -> Vec<Topic> {
+    let mut clusters: Vec<Cluster> = Vec::new();
+    let mut other: Vec<usize> = Vec::new();
+    for (i, embedding) in embeddings.iter().enumerate().take(pages.len()) {
+        match embedding {
+            Some(embedding) => clusters.push(Cluster {
+                members: vec![i],
+                centre: embedding.clone(),
+            }),
+            None => other.push(i),
+        }
+    }
+    other.extend(embeddings.len()..pages.len());
+
+    // All pairs each round, but there are only a hundred results at most.
+    while clusters.len() > 1 {
+        let mut closest = (f32::MAX, 0, 0);
+        for a in 0..clusters.len() {
+            for b in a + 1..clusters.len() {
+                let d = distance_cosine(&clusters[a].centre, &clusters[b].centre);
+                if d < closest.0 {
+                    closest = (d, a, b);
+                }
+            }
+        }
+        let (distance, a, b) = closest;
+        if distance > MERGE_DISTANCE && clusters.len() <= MAX_TOPICS {
+            break;
+        }
+        let merged = clusters.remove(b);
+        let into = &mut clusters[a];
+        // The centres are weighted by the number of members.
+        let (na, nb) = (into.members.len() as f32, merged.members.len() as f32);
+        for (x, y) in into.centre.iter_mut().zip(&merged.centre) {
+            *x = *x * na + y * nb;
+        }
+        normalize(&mut into.centre);
+        into.members.extend(merged.members);
+        into.members.sort();
+    }
+    clusters.sort_by_key(|c| c.members[0]);
+
+    let titles: Vec<&str> = pages.iter().map(|p| p.title.as_str()).collect();
+    let labels: Vec<String> = clusters
+        .iter()
+        .map(|c| label(&titles, &c.members, query))
+        .collect();
+    let references: Vec<Vec<(String, usize)>> = clusters
+        .iter()
+        .map(|c| {
+            // Every member has an embedding.
+            let distance = |i: usize| {
+                embeddings[i]
+                    .as_ref()
+                    .map_or(f32::MAX, |e| distance_cosine(e, &c.centre))
+            };
+            let mut members = c.members.clone();
+            members.sort_by(|&a, &b| distance(a).total_cmp(&distance(b)));
+            members.truncate(TOPIC_REFERENCES);
+            members
+                .iter()
+                .map(|&i| (pages[i].instance_id.clone(), pages[i].page_id))
+                .collect()
+        })
+        .collect();
+
+    let mut pages: Vec<Option<FoundPage>> = pages.into_iter().map(Some).collect();
+    let mut take = |members: &[usize]| -> Vec<FoundPage> {
+        members.iter().filter_map(|&i| pages[i].take()).collect()
+    };
+    let mut topics: Vec<Topic> = clusters
+        .iter()
+        .zip(labels)
+        .zip(references)
+        .map(|((c, label), references)| Topic {
+            label,
+            pages: take(&c.members),
+            references,
+        })
+        .collect();
+    if !other.is_empty() {
+        topics.push(Topic {
+            label: "Other".to_string(),
+            pages: take(&other),
+            references: Vec::new(),
+        });
+    }
+    topics
+}
+
+/** The normalized mean of the embeddings, None when there are none. */
+pub fn centre<'a>(embeddings: impl IntoIterator<Item = &'a Vec<f32>>) -> Option<Vec<f32>> {
+    let mut sum: Option<Vec<f32>> = None;
+    for embedding in embeddings {
+        match &mut sum {
+            Some(sum) => sum.iter_mut().zip(embedding).for_each(|(x, y)| *x += y),
+            None => sum = Some(embedding.clone()),
+        }
+    }
+    let mut sum = sum?;
+    normalize(&mut sum);
+    Some(sum)
+}
+
+/**
+ * The words that are common in the titles of the members, but not in the titles of the
+ * other results. Falls back to the title of the best match.
+ */
+fn label(titles: &[&str], members: &[usize], query: &str) -> String {
+    let terms = query_terms(query);
+    // For each word the number of titles it occurs in, and how it was written first.
+    let mut everywhere: HashMap<String, usize> = HashMap::new();
+    let mut here: HashMap<String, (usize, &str)> = HashMap::new();
+    for (i, title) in titles.iter().enumerate() {
+        let mut seen: Vec<String> = Vec::new();
+        for (_, word) in word_indices(title) {
+            let lower = word.to_lowercase();
+            if lower.chars().count() < 3
+                || lower.chars().all(|c| c.is_numeric())
+                || STOP_WORDS.contains(&lower.as_str())
+                || terms.contains(&lower)
+                || seen.contains(&lower)
+            {
+                continue;
+            }
+            *everywhere.entry(lower.clone()).or_default() += 1;
+            if members.contains(&i) {
+                here.entry(lower.clone()).or_insert((0, word)).0 += 1;
+            }
+            seen.push(lower);
+        }
+    }
+
+    let total = titles.len() as f32;
+    let mut scored: Vec<(f32, usize, &str)> = here
+        .iter()
+        // A word in a single title of a bigger topic is not what it is about.
+        .filter(|(_, (count, _))| *count > 1 || members.len() == 1)
+        .map(|(lower, (count, word))| {
+            let idf = (1.0 + total / everywhere[lower] as f32).ln();
+            (*count as f32 * idf, *count, *word)
+        })
+        .collect();
+    // Ties are broken on the word, so the label does not change between requests.
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.2.cmp(b.2)));
+
+    let words: Vec<&str> = scored.iter().take(LABEL_WORDS).map(|s| s.2).collect();
+    if words.is_empty() {
+        return titles[members[0]].to_string();
+    }
+    words.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(page_id: usize, title: &str) -> FoundPage {
+        FoundPage {
+            instance_id: String::new(),
+            page_id,
+            distance: page_id as f32 / 100.0,
+            url: format!("https://example.com/{}", page_id),
+            title: title.to_string(),
+            text: String::new(),
+            fetched: 0,
+            language: String::new(),
+            host: "example.com".to_string(),
+            duplicates: 0,
+            inserted: 0,
+        }
+    }
+
+    /** A unit vector along one axis, so embeddings are either the same or far apart. */
+    fn axis(k: usize) -> Option<Vec<f32>> {
+        let mut v = vec![0.0; 10];
+        v[k] = 1.0;
+        Some(v)
+    }
+
+    fn page_ids(topic: &Topic) -> Vec<usize> {
+        topic.pages.iter().map(|p| p.page_id).collect()
+    }
+
+    #[test]
+    fn at_most_max_topics() {
+        let pages: Vec<FoundPage> = (0..10).map(|i| page(i, "Some title")).collect();
+        let embeddings = (0..10).map(axis).collect();
+        let topics = cluster(pages, embeddings, "");
+        assert!(topics.len() <= MAX_TOPICS);
+        let mut ids: Vec<usize> = topics.iter().flat_map(page_ids).collect();
+        ids.sort();
+        assert_eq!(ids, (0..10).collect::<Vec<usize>>());
+    }
+
+    fn two_topics() -> (Vec<FoundPage>, Vec<Option<Vec<f32>>>) {
+        let pages = vec![
+            page(0, "Rust compiler guide"),
+            page(1, "Python snakes"),
+            page(2, "Rust compiler errors"),
+            page(3, "Python tutorial"),
+            page(4, "No embedding"),
+            page(5, "Beyond the embeddings"),
+        ];
+        // The last page has no entry at all.
+        let embeddings = vec![axis(0), axis(1), axis(0), axis(1), None];
+        (pages, embeddings)
+    }
+
+    #[test]
+    fn pages_without_embeddings_go_to_other() {
+        let (pages, embeddings) = two_topics();
+        let topics = cluster(pages, embeddings, "");
+        assert_eq!(topics.len(), 3);
+        assert_eq!(page_ids(&topics[0]), vec![0, 2]);
+        assert_eq!(page_ids(&topics[1]), vec![1, 3]);
+        assert_eq!(topics[2].label, "Other");
+        assert_eq!(page_ids(&topics[2]), vec![4, 5]);
+        assert!(topics[2].references.is_empty());
+        assert_eq!(topics[0].references.len(), 2);
+    }
+
+    #[test]
+    fn labels_are_common_title_words() {
+        let (pages, embeddings) = two_topics();
+        let topics = cluster(pages.clone(), embeddings.clone(), "");
+        // Words in a single title of the topic are left out, ties are ordered on the word.
+        assert_eq!(topics[0].label, "Rust, compiler");
+        assert_eq!(topics[1].label, "Python");
+        // Words of the query say nothing about the topic.
+        let topics = cluster(pages, embeddings, "rust");
+        assert_eq!(topics[0].label, "compiler");
+    }
+
+    #[test]
+    fn labels_do_not_change_between_runs() {
+        let (pages, embeddings) = two_topics();
+        let first: Vec<String> = cluster(pages.clone(), embeddings.clone(), "")
+            .into_iter()
+            .map(|t| t.label)
+            .collect();
+        // Every run hashes the words differently.
+        for _ in 0..20 {
+            let labels: Vec<String> = cluster(pages.clone(), embeddings.clone(), "")
+                .into_iter()
+                .map(|t| t.label)
+                .collect();
+            assert_eq!(labels, first);
+        }
+    }
+
+    #[test]
+    fn centre_is_normalized() {
+        assert_eq!(centre(Vec::<&Vec<f32>>::new()), None);
+        let (a, b) = (axis(0).unwrap(), axis(1).unwrap());
+        let c = centre([&a, &b]).unwrap();
+        assert!((c[0] - 0.5f32.sqrt()).abs() < 1e-6);
+        assert!((c[1] - 0.5f32.sqrt()).abs() < 1e-6);
+    }
+}
@@ -23,58 +23,60 @@ use anyhow::{bail, ensure};
 use num::Num;
 use rand::Rng;
 
-pub const EM_LEN: usize = 384; // 300 for fasttext
-
-pub type Embedding<T> = [T; EM_LEN];
+/**
+ * An embedding. Its length depends on the model, see Config::embedding_dimension.
+ */
+pub type Embedding<T> = [T];
 
 fn f32_to_i16(x: f32) -> i16 {
     (x * i16::MAX as f32).round() as i16
 }
 
 pub trait ToI16 {
-    fn to_i16(&self) -> Embedding<i16>;
+    fn to_i16(&self) -> Vec<i16>;
 }
 
 impl ToI16 for Embedding<f32> {
-    fn to_i16(&self) -> Embedding<i16> {
-        let mut result: [i16; EM_LEN] = [0; EM_LEN];
-        for i in 0..EM_LEN {
-            result[i] = f32_to_i16(self[i]);
-        }
-        result
+    fn to_i16(&self) -> Vec<i16> {
+        self.iter().map(|x| f32_to_i16(*x)).collect()
     }
 }
 
 const I24_MAX: u32 = 0x7FFFFF;
 
 pub trait ToFrom24 {
-    fn from24(data: &[u8]) -> anyhow::Result<Embedding<f32>>;
+    fn from24(data: &[u8]) -> anyhow::Result<Vec<f32>>;
     fn to24(&self) -> Vec<u8>;
 }
 
 impl ToFrom24 for Vec<f32> {
     /** Convert the embedding back into f32 from i24. */
-    fn from24(data: &[u8]) -> anyhow::Result<Embedding<f32>> {
-        let mut result = [0.0f32; EM_LEN];
-        for i in 0..EM_LEN {
+    fn from24(data: &[u8]) -> anyhow::Result<Vec<f32>> {
+        ensure!(
+            data.len() % 3 == 0,
+            "Not an i24 embedding: {} bytes",
+            data.len()
+        );
+        let mut result = Vec::with_capacity(data.len() / 3);
+        for bytes in data.chunks_exact(3) {
             let mut v: i32 = 0;
-            v |= data[i * 3] as i32;
-            v |= ((data[i * 3 + 1] as i32) << 8) as i32;
-            v |= ((data[i * 3 + 2] as i32) << 16) as i32;
+            v |= bytes[0] as i32;
+            v |= ((bytes[1] as i32) << 8) as i32;
+            v |= ((bytes[2] as i32) << 16) as i32;
             // Sign extend.
-            if data[i * 3 + 2] & 0b10000000 > 0 {
+            if bytes[2] & 0b10000000 > 0 {
                 v |= 0xFF;
             }
-            result[i] = (v as f64 / I24_MAX as f64 * 2.0 - 1.0) as f32;
+            result.push((v as f64 / I24_MAX as f64 * 2.0 - 1.0) as f32);
         }
         ensure!(is_normalized(&result), "Embedding is not normalized");
         Ok(result)
     }
     /** Convert the embedding into i24 to save space. */
     fn to24(&self) -> Vec<u8> {
-        let mut result = Vec::with_capacity(EM_LEN);
-        for i in 0..EM_LEN {
-            let v = (((self[i] as f64 + 1.0) / 2.0) * I24_MAX as f64) as i32;
+        let mut result = Vec::with_capacity(self.len() * 3);
+        for x in self {
+            let v = (((*x as f64 + 1.0) / 2.0) * I24_MAX as f64) as i32;
             let a = v & 0xFF;
             let b = (v >> 8) & 0xFF;
             let c = (v >> 16) & 0xFF;
@@ -127,16 +129,16 @@ impl SupportedNum for f32 {}
  */
 pub fn distance_cosine(a: &Embedding<f32>, b: &Embedding<f32>) -> f32 {
     let mut result: f32 = 0.0;
-    for i in 0..EM_LEN {
-        result += a[i] * b[i]
+    for (a, b) in zip(a, b) {
+        result += a * b
     }
     1.0 - result
 }
 
 pub fn distance_upper_bound(a: &Embedding<f32>, b: &Embedding<f32>, _limit: f32) -> f32 {
     let mut result: f32 = 0.0;
-    for i in 0..EM_LEN {
-        result += (a[i] - b[i]).powf(2.0);
+    for (a, b) in zip(a, b) {
+        result += (a - b).powf(2.0);
         // Float additions are not vectorized anyway.
         // This is < 10% faster, so not really worth it.
         // if result > limit {
@@ -148,16 +150,16 @@ pub fn distance_upper_bound(a: &Embedding<f32>, b: &Embedding<f32>, _limit: f32)
 
 pub fn distance_reduced(a: &Embedding<f32>, b: &Embedding<f32>) -> f32 {
     let mut result: u32 = 0;
-    for i in 0..EM_LEN {
-        result += (f32_to_i16(a[i]) as i32 - f32_to_i16(b[i]) as i32).pow(2) as u32;
+    for (a, b) in zip(a, b) {
+        result += (f32_to_i16(*a) as i32 - f32_to_i16(*b) as i32).pow(2) as u32;
     }
     result as f32
 }
 
-pub fn distance_i8(a: &[i8; EM_LEN], b: &[i8; EM_LEN]) -> u32 {
+pub fn distance_i8(a: &Embedding<i8>, b: &Embedding<i8>) -> u32 {
     let mut result: u32 = 0;
-    for i in 0..EM_LEN {
-        result += (a[i] as i32 - b[i] as i32).pow(2) as u32;
+    for (a, b) in zip(a, b) {
+        result += (*a as i32 - *b as i32).pow(2) as u32;
     }
     result
 }
@@ -165,21 +167,18 @@ pub fn distance_i8(a: &[i8; EM_LEN], b: &[i8; EM_LEN]) -> u32 {
 /**
  * Random unit length vector.
  */
-pub fn random_address() -> Embedding<f32> {
+pub fn random_address(dimension: usize) -> Vec<f32> {
     let mut rng = rand::thread_rng();
-    let mut address: Embedding<f32> = [0.0; EM_LEN];
-    for x in 0..EM_LEN {
-        address[x] = rng.gen();
-    }
+    let mut address: Vec<f32> = (0..dimension).map(|_| rng.gen()).collect();
     let length = vector_length(&address);
-    for x in 0..EM_LEN {
-        address[x] /= length;
+    for x in address.iter_mut() {
+        *x /= length;
     }
     address
 }
 
 pub fn vector_length(v: &Embedding<f32>) -> f32 {
-    v.distance(&[0.0; EM_LEN]).sqrt()
+    v.iter().map(|x| x * x).sum::<f32>().sqrt()
 }
 
 const MAX_VECTOR_DELTA: f32 = 0.01f32;
@@ -196,21 +195,102 @@ pub fn normalize(v: &mut Vec<f32>) {
     v.iter_mut().for_each(|x| *x /= length);
 }
 
-pub unsafe fn bytes_to_embedding(p: &[u8; EM_LEN * 4]) -> anyhow::Result<&[f32; EM_LEN]> {
-    let emb = ::core::slice::from_raw_parts(p.as_ptr() as *const f32, EM_LEN).try_into()?;
-    if !is_normalized(emb) {
+/** Read an embedding stored as f32 values, little endian. */
+pub fn bytes_to_embedding(p: &[u8]) -> anyhow::Result<Vec<f32>> {
+    ensure!(p.len() % 4 == 0, "Not an f32 embedding: {} bytes", p.len());
+    let emb: Vec<f32> = p
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    if !is_normalized(&emb) {
         bail!("Vector is not normalized");
     }
     Ok(emb)
 }
 
-pub unsafe fn embedding_to_bytes(p: &[f32; EM_LEN]) -> anyhow::Result<&[u8; EM_LEN * 4]> {
+/** Store an embedding as f32 values, little endian. */
+pub fn embedding_to_bytes(p: &Embedding<f32>) -> anyhow::Result<Vec<u8>> {
     if !is_normalized(p) {
         bail!("Vector is not normalized");
     }
-    Ok(::core::slice::from_raw_parts(p.as_ptr() as *const u8, EM_LEN * 4).try_into()?)
+    Ok(p.iter().flat_map(|v| v.to_le_bytes()).collect())
+}
+
+fn i16_to_f32(x: i16) -> f32 {
+    x as f32 / i16::MAX as f32
+}
+
+pub trait FromI16 {
+    fn from_i16(&self) -> Vec<f32>;
+}
+
+impl FromI16 for Embedding<i16> {
+    fn from_i16(&self) -> Vec<f32> {
+        self.iter().map(|x| i16_to_f32(*x)).collect()
+    }
 }
 
-pub unsafe fn vector_embedding_to_bytes(p: &Vec<f32>) -> anyhow::Result<&[u8; EM_LEN * 4]> {
-    embedding_to_bytes(p.as_slice().try_into()?)
+/**
+ * How embeddings are stored in the database. The format of a stored embedding is
+ * recognised by its length, so a database can contain a mix of formats.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmbeddingStorage {
+    /** 4 bytes per value, lossless. */
+    F32,
+    /** 3 bytes per value, the format we also use on the network. */
+    I24,
+    /** 2 bytes per value, little endian. */
+    I16,
+}
+
+impl EmbeddingStorage {
+    pub fn parse(name: &str) -> anyhow::Result<EmbeddingStorage> {
+        Ok(match name {
+            "f32" => EmbeddingStorage::F32,
+            "i24" => EmbeddingStorage::I24,
+            "i16" => EmbeddingStorage::I16,
+            _ => bail!("Unknown embedding storage format: {}", name),
+        })
+    }
+
+    /** Number of bytes an embedding of 'dimension' values takes in this format. */
+    pub fn byte_len(&self, dimension: usize) -> usize {
+        match self {
+            EmbeddingStorage::F32 => dimension * 4,
+            EmbeddingStorage::I24 => dimension * 3,
+            EmbeddingStorage::I16 => dimension * 2,
+        }
+    }
+
+    pub fn encode(&self, embedding: &Embedding<f32>) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            EmbeddingStorage::F32 => embedding_to_bytes(embedding)?,
+            EmbeddingStorage::I24 => embedding.to_vec().to24(),
+            EmbeddingStorage::I16 => embedding
+                .to_i16()
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect(),
+        })
+    }
+
+    /** Decode an embedding of 'dimension' values in any of the formats. */
+    pub fn decode(data: &[u8], dimension: usize) -> anyhow::Result<Vec<f32>> {
+        if data.len() == EmbeddingStorage::F32.byte_len(dimension) {
+            bytes_to_embedding(data)
+        } else if data.len() == EmbeddingStorage::I24.byte_len(dimension) {
+            Vec::<f32>::from24(data)
+        } else if data.len() == EmbeddingStorage::I16.byte_len(dimension) {
+            let values: Vec<i16> = data
+                .chunks_exact(2)
+                .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+                .collect();
+            let result = values.from_i16();
+            ensure!(is_normalized(&result), "Embedding is not normalized");
+            Ok(result)
+        } else {
+            bail!("Embedding has an unknown length: {}", data.len());
+        }
+    }
 }
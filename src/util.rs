@@ -57,3 +57,141 @@ pub fn now() -> u64 {
         .expect("Time travelled to before the epoch")
         .as_secs()
 }
+
+/** Days since 1970-01-01 for a date in the proleptic Gregorian calendar. */
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // See http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/** Inverse of days_from_civil, returns (year, month, day). */
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/** Parse a UTC timestamp like '2023-05-27T05:12:34Z', as used in WARC-Date, into seconds. */
+pub fn parse_timestamp(s: &str) -> Option<u64> {
+    let s = s.trim().trim_end_matches('Z');
+    let (date, time) = s.split_once('T').unwrap_or((s, "00:00:00"));
+    let mut date = date.splitn(3, '-').map(|x| x.parse::<i64>());
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time.splitn(3, ':').map(|x| x.parse::<f64>());
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let seconds = days_from_civil(year, month, day) * 86400
+        + hour as i64 * 3600
+        + minute as i64 * 60
+        + second as i64;
+    u64::try_from(seconds).ok()
+}
+
+/** Format a timestamp in seconds as '2023-05-27T05:12:34Z'. */
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (year, month, day) = civil_from_days((timestamp / 86400) as i64);
+    let seconds = timestamp % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/**
+ * 64 bit FNV-1a hash of the text, as hex. Unlike the hashers in std the result
+ * is stable, so it can be stored.
+ */
+pub fn content_hash(text: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in text.as_bytes() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/** The host part of the URL, or an empty string if it can't be parsed. */
+pub fn host_of(url: &str) -> String {
+    url::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_from_civil_counts_leap_days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 2, 29), 11016);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        // 1900 is not a leap year.
+        assert_eq!(days_from_civil(1900, 2, 28), -25509);
+        assert_eq!(days_from_civil(1900, 3, 1), -25508);
+        for days in [-25509, -25508, -1, 0, 11016, 11017, 19504] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn parse_timestamp_reads_warc_dates() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("2000-02-29T00:00:00Z"), Some(951782400));
+        assert_eq!(parse_timestamp("1999-12-31T23:59:59Z"), Some(946684799));
+        assert_eq!(parse_timestamp("2000-01-01T00:00:00Z"), Some(946684800));
+        assert_eq!(parse_timestamp(" 2023-05-27T03:12:34Z "), Some(1685157154));
+        // Without a time it is the start of the day.
+        assert_eq!(parse_timestamp("2023-05-27"), Some(1685145600));
+    }
+
+    #[test]
+    fn parse_timestamp_rejects_invalid_input() {
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+        assert_eq!(parse_timestamp("2023-05"), None);
+        assert_eq!(parse_timestamp("2023-13-01"), None);
+        assert_eq!(parse_timestamp("2023-00-10"), None);
+        assert_eq!(parse_timestamp("2023-05-32"), None);
+        assert_eq!(parse_timestamp("2023-05-27T03:12"), None);
+        // Before the epoch.
+        assert_eq!(parse_timestamp("1900-03-01"), None);
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z"), None);
+    }
+
+    #[test]
+    fn format_timestamp_is_the_inverse_of_parse() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(946684799), "1999-12-31T23:59:59Z");
+        for s in [
+            "2000-03-01T12:00:00Z",
+            "2023-05-27T03:12:34Z",
+            "2024-12-31T23:59:59Z",
+        ] {
+            assert_eq!(format_timestamp(parse_timestamp(s).unwrap()), s);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+/*
+   Copyright 2023 Krol Inventions B.V.
+
+   This file is part of DawnSearch.
+
+   DawnSearch is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   DawnSearch is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with DawnSearch.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::mpsc::sync_channel;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use dawnsearch::config::Config;
+use dawnsearch::net::http_service::router;
+use tower::ServiceExt;
+
+/** Requests that must be refused before they reach the search service. */
+async fn assert_bad_request(uri: &str) {
+    let (tx, rx) = sync_channel(10);
+    let response = router(tx, Config::load(""))
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+    assert!(rx.try_recv().is_err(), "{} reached the search service", uri);
+}
+
+#[tokio::test]
+async fn huge_page_number() {
+    assert_bad_request("/?q=cats&page=18446744073709551615").await;
+    assert_bad_request("/?s=:1&page=18446744073709551615").await;
+}
+
+#[tokio::test]
+async fn page_beyond_max_results() {
+    assert_bad_request("/?q=cats&page=0").await;
+    assert_bad_request("/?q=cats&page=6").await;
+}
+
+#[tokio::test]
+async fn api_paging_out_of_range() {
+    assert_bad_request("/api/search?q=cats&page=18446744073709551615").await;
+    assert_bad_request("/api/search?q=cats&limit=100&page=2").await;
+    assert_bad_request("/api/like?s=:1&limit=0").await;
+}